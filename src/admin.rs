@@ -0,0 +1,459 @@
+use crate::database;
+use crate::datastructures::config::{Config, Telegram};
+use crate::datastructures::QueryResult;
+use crate::display_time;
+use crate::raw_lines::SharedRawLines;
+use crate::roster::{format_roster, sessions_of, ChannelMap, ClientMap, RosterFormat};
+use crate::sanitize::NicknameSanitize;
+use chrono::{TimeZone, Utc};
+use log::{debug, error, info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use teloxide::adaptors::DefaultParseMode;
+use teloxide::prelude::*;
+use teloxide::types::ParseMode;
+use teloxide::utils::command::BotCommands;
+use teloxide::utils::html;
+use tokio::sync::{mpsc, oneshot};
+
+/// Leave some space for `<pre>` tag and escaped characters
+const RAW_LINES_CHUNK: usize = 3500;
+/// Clients listed when `/lastseen` matches several database ids
+const LAST_SEEN_LIMIT: usize = 5;
+
+/// Shared switch which make staff thread stop parsing notify events.
+///
+/// Connection and keepalive are still maintained while paused.
+#[derive(Debug, Default)]
+pub struct PauseControl {
+    paused: AtomicBool,
+    since: std::sync::Mutex<Option<Instant>>,
+}
+
+impl PauseControl {
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Return `false` if already paused
+    pub fn pause(&self) -> bool {
+        let mut since = self.since.lock().unwrap();
+        if self.paused.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        since.replace(Instant::now());
+        true
+    }
+
+    /// Return how long observer paused, `None` if not paused
+    pub fn resume(&self) -> Option<Duration> {
+        let mut since = self.since.lock().unwrap();
+        if !self.paused.swap(false, Ordering::SeqCst) {
+            return None;
+        }
+        since.take().map(|instant| instant.elapsed())
+    }
+}
+
+/// Command which should be executed by staff thread on the query connection.
+pub enum StaffCommand {
+    Broadcast(String, oneshot::Sender<QueryResult<()>>),
+    /// Message relayed from telegram to server chat
+    ServerText(String, oneshot::Sender<QueryResult<()>>),
+    Kick(i64, oneshot::Sender<QueryResult<()>>),
+    /// Client id and ban duration in seconds
+    Ban(i64, u64, oneshot::Sender<QueryResult<()>>),
+    /// Query server info and emit periodic status
+    Status,
+}
+
+#[derive(BotCommands, Clone)]
+#[command(rename = "lowercase", description = "Admin commands:")]
+pub enum AdminCommand {
+    #[command(description = "Stop processing notify events.")]
+    Pause,
+    #[command(description = "Resume processing notify events.")]
+    Resume,
+    #[command(description = "List online clients, optional format: list or compact.")]
+    Online(String),
+    #[command(description = "Dump last received raw protocol lines.")]
+    RawLines,
+    #[command(description = "List current connections of a unique identifier.")]
+    Sessions(String),
+    #[command(description = "Send a global message to TeamSpeak server.")]
+    Broadcast(String),
+    #[command(description = "Kick a client from server: /kick <clid>")]
+    Kick(String),
+    #[command(description = "Ban a client: /ban <clid> <seconds>, 0 seconds means permanent.")]
+    Ban(String),
+    #[command(description = "Show last disconnect of a client: /lastseen <nickname or uid>")]
+    LastSeen(String),
+}
+
+pub struct AdminContext {
+    admin_chats: Vec<i64>,
+    /// Notification target chats, only allowed to use read only commands
+    target_chats: Vec<i64>,
+    admin_users: Vec<u64>,
+    pause_control: Arc<PauseControl>,
+    client_map: ClientMap,
+    channel_map: ChannelMap,
+    roster_format: RosterFormat,
+    relative_time: bool,
+    nickname_sanitize: NicknameSanitize,
+    raw_lines: Option<SharedRawLines>,
+    staff_sender: mpsc::Sender<StaffCommand>,
+    bridge_to_ts: bool,
+    database_path: Option<String>,
+}
+
+impl AdminContext {
+    /// Admin commands operate on state of primary server
+    pub fn new(
+        config: &Config,
+        telegram: &Telegram,
+        pause_control: Arc<PauseControl>,
+        client_map: ClientMap,
+        channel_map: ChannelMap,
+        raw_lines: Option<SharedRawLines>,
+        staff_sender: mpsc::Sender<StaffCommand>,
+    ) -> Self {
+        Self {
+            admin_chats: telegram.admin_chats().clone(),
+            target_chats: telegram.targets(),
+            admin_users: telegram.admin_users().clone(),
+            pause_control,
+            client_map,
+            channel_map,
+            roster_format: telegram.roster_format(),
+            relative_time: config.misc().relative_time(),
+            nickname_sanitize: config.misc().nickname_sanitize().clone(),
+            raw_lines,
+            staff_sender,
+            bridge_to_ts: telegram.bridge_to_ts(),
+            database_path: config
+                .database()
+                .as_ref()
+                .map(|database| database.path().to_string()),
+        }
+    }
+
+    fn is_authorized(&self, msg: &Message, cmd: &AdminCommand) -> bool {
+        self.admin_chats.contains(&msg.chat.id.0)
+            || msg
+                .from()
+                .map(|user| self.admin_users.contains(&user.id.0))
+                .unwrap_or(false)
+            || (matches!(cmd, AdminCommand::Online(_))
+                && self.target_chats.contains(&msg.chat.id.0))
+    }
+
+    /// Mistyped or foreign bot commands are never relayed to server chat
+    fn should_bridge(&self, msg: &Message) -> bool {
+        self.bridge_to_ts
+            && self.target_chats.contains(&msg.chat.id.0)
+            && !msg
+                .text()
+                .map(str::trim_start)
+                .map_or(false, |text| text.starts_with('/') || text.starts_with('!'))
+    }
+}
+
+/// Parse `!online [format]`, which is accepted as alias of `/online`
+fn parse_bang_command(msg: Message) -> Option<AdminCommand> {
+    let text = msg.text()?.trim();
+    let rest = text.strip_prefix("!online")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(AdminCommand::Online(rest.trim().to_string()))
+}
+
+/// Relay plain text message of target chat to TeamSpeak server chat
+async fn handle_bridge(msg: Message, ctx: Arc<AdminContext>) -> ResponseResult<()> {
+    let text = match msg.text() {
+        Some(text) if !text.trim().is_empty() => text,
+        _ => return Ok(()),
+    };
+    let message = match msg.from() {
+        Some(user) => format!("[TG] {}: {}", user.full_name(), text),
+        None => format!("[TG] {}", text),
+    };
+    let (sender, receiver) = oneshot::channel();
+    if ctx
+        .staff_sender
+        .send(StaffCommand::ServerText(message, sender))
+        .await
+        .is_err()
+    {
+        warn!("Staff thread is not running, drop bridged message");
+        return Ok(());
+    }
+    match receiver.await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => warn!("Got error while relay message to server chat: {:?}", e),
+        Err(_) => warn!("Staff thread dropped the bridged message"),
+    }
+    Ok(())
+}
+
+/// Send command to staff thread and describe the query result
+async fn execute_staff_command(
+    ctx: &AdminContext,
+    action: &str,
+    command: impl FnOnce(oneshot::Sender<QueryResult<()>>) -> StaffCommand,
+) -> String {
+    let (sender, receiver) = oneshot::channel();
+    if ctx.staff_sender.send(command(sender)).await.is_err() {
+        return "Staff thread is not running".to_string();
+    }
+    match receiver.await {
+        Ok(Ok(_)) => format!("{} succeeded", action),
+        Ok(Err(e)) => format!("{} failed: {}", action, html::escape(&e.to_string())),
+        Err(_) => "Staff thread dropped the request".to_string(),
+    }
+}
+
+async fn handle_command(
+    bot: DefaultParseMode<Bot>,
+    msg: Message,
+    cmd: AdminCommand,
+    ctx: Arc<AdminContext>,
+) -> ResponseResult<()> {
+    if !ctx.is_authorized(&msg, &cmd) {
+        debug!(
+            "Rejected command from chat {:?} user {:?}",
+            msg.chat.id,
+            msg.from().map(|user| user.id)
+        );
+        return Ok(());
+    }
+    let reply = match cmd {
+        AdminCommand::Pause => {
+            if ctx.pause_control.pause() {
+                info!("Event processing paused by admin");
+                "Event processing paused".to_string()
+            } else {
+                "Already paused".to_string()
+            }
+        }
+        AdminCommand::Resume => match ctx.pause_control.resume() {
+            Some(duration) => {
+                info!("Event processing resumed after {:?}", duration);
+                format!(
+                    "Event processing resumed, paused for {}s",
+                    duration.as_secs()
+                )
+            }
+            None => "Not paused".to_string(),
+        },
+        AdminCommand::Online(format) => {
+            let format = if format.trim().is_empty() {
+                ctx.roster_format
+            } else {
+                match format.parse() {
+                    Ok(format) => format,
+                    Err(e) => {
+                        bot.send_message(msg.chat.id, html::escape(&e.to_string()))
+                            .send()
+                            .await?;
+                        return Ok(());
+                    }
+                }
+            };
+            let messages = format_roster(
+                &*ctx.client_map.read().await,
+                &*ctx.channel_map.read().await,
+                format,
+                &ctx.nickname_sanitize,
+                ctx.relative_time,
+            );
+            for message in messages {
+                bot.send_message(msg.chat.id, html::escape(&message))
+                    .send()
+                    .await?;
+            }
+            return Ok(());
+        }
+        AdminCommand::RawLines => match &ctx.raw_lines {
+            Some(raw_lines) => {
+                let lines = raw_lines.lock().unwrap().lines();
+                if lines.is_empty() {
+                    "No raw line recorded".to_string()
+                } else {
+                    let mut messages = vec![String::new()];
+                    for line in lines {
+                        let current = messages.last_mut().unwrap();
+                        if !current.is_empty() && current.len() + line.len() + 1 > RAW_LINES_CHUNK {
+                            messages.push(String::new());
+                        }
+                        let current = messages.last_mut().unwrap();
+                        current.push_str(&line);
+                        current.push('\n');
+                    }
+                    for message in messages {
+                        bot.send_message(msg.chat.id, html::code_block(&message))
+                            .send()
+                            .await?;
+                    }
+                    return Ok(());
+                }
+            }
+            None => "Raw line buffer is disabled".to_string(),
+        },
+        AdminCommand::Sessions(unique_identifier) => {
+            let unique_identifier = unique_identifier.trim();
+            let client_map = ctx.client_map.read().await;
+            let channel_map = ctx.channel_map.read().await;
+            let sessions = sessions_of(&client_map, unique_identifier);
+            if sessions.is_empty() {
+                format!(
+                    "No session found for <code>{}</code>",
+                    html::escape(unique_identifier)
+                )
+            } else {
+                let mut reply = format!(
+                    "{} session(s) of <code>{}</code>:",
+                    sessions.len(),
+                    html::escape(unique_identifier)
+                );
+                for (client_id, client) in sessions {
+                    reply.push_str(&format!(
+                        "\n{}: <b>{}</b> in {}",
+                        client_id,
+                        html::escape(&ctx.nickname_sanitize.apply(client.nickname())),
+                        html::escape(
+                            channel_map
+                                .get(&client.channel_id())
+                                .map(|channel| channel.channel_name())
+                                .unwrap_or("unknown channel")
+                        )
+                    ));
+                }
+                reply
+            }
+        }
+        AdminCommand::Kick(args) => match args.trim().parse::<i64>() {
+            Ok(client_id) => {
+                info!("Kick client {} by admin", client_id);
+                execute_staff_command(&ctx, "Kick", |sender| StaffCommand::Kick(client_id, sender))
+                    .await
+            }
+            Err(_) => "Usage: /kick &lt;clid&gt;".to_string(),
+        },
+        AdminCommand::Ban(args) => {
+            let mut args = args.split_whitespace();
+            match (
+                args.next().and_then(|arg| arg.parse::<i64>().ok()),
+                args.next().and_then(|arg| arg.parse::<u64>().ok()),
+                args.next(),
+            ) {
+                (Some(client_id), Some(seconds), None) => {
+                    info!("Ban client {} for {}s by admin", client_id, seconds);
+                    execute_staff_command(&ctx, "Ban", |sender| {
+                        StaffCommand::Ban(client_id, seconds, sender)
+                    })
+                    .await
+                }
+                _ => "Usage: /ban &lt;clid&gt; &lt;seconds&gt;".to_string(),
+            }
+        }
+        AdminCommand::Broadcast(message) => {
+            let message = message.trim();
+            if message.is_empty() {
+                "Usage: /broadcast &lt;message&gt;".to_string()
+            } else {
+                execute_staff_command(&ctx, "Broadcast", |sender| {
+                    StaffCommand::Broadcast(message.to_string(), sender)
+                })
+                .await
+            }
+        }
+        AdminCommand::LastSeen(identifier) => {
+            let identifier = identifier.trim().to_string();
+            match (&ctx.database_path, identifier.is_empty()) {
+                (None, _) => "Event database is disabled".to_string(),
+                (Some(_), true) => "Usage: /lastseen &lt;nickname or uid&gt;".to_string(),
+                (Some(path), false) => {
+                    let path = path.clone();
+                    let query = identifier.clone();
+                    let result = tokio::task::spawn_blocking(move || {
+                        database::last_seen(&path, &query, LAST_SEEN_LIMIT)
+                    })
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|result| result);
+                    match result {
+                        Ok(seen) if seen.is_empty() => format!(
+                            "No disconnect recorded for <code>{}</code>",
+                            html::escape(&identifier)
+                        ),
+                        Ok(seen) => seen
+                            .iter()
+                            .map(|seen| {
+                                format!(
+                                    "<b>{}</b> (dbid {}) last left at {}",
+                                    html::escape(&ctx.nickname_sanitize.apply(&seen.nickname)),
+                                    seen.client_database_id,
+                                    Utc.timestamp_opt(seen.timestamp, 0)
+                                        .single()
+                                        .map_or_else(|| "unknown time".to_string(), display_time)
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\n"),
+                        Err(e) => {
+                            error!("Got error while query last seen: {:?}", e);
+                            "Failed to query event database".to_string()
+                        }
+                    }
+                }
+            }
+        }
+    };
+    bot.send_message(msg.chat.id, reply).send().await?;
+    Ok(())
+}
+
+pub async fn admin_thread(
+    token: String,
+    server: String,
+    context: AdminContext,
+) -> anyhow::Result<()> {
+    if token.is_empty() {
+        debug!("Token is empty, admin command disabled.");
+        return Ok(());
+    }
+    if context.admin_chats.is_empty()
+        && context.admin_users.is_empty()
+        && context.target_chats.is_empty()
+    {
+        info!("No admin chats, users or target chats configured, admin command disabled.");
+        return Ok(());
+    }
+    let bot = Bot::new(token)
+        .set_api_url(server.parse()?)
+        .parse_mode(ParseMode::Html);
+
+    let handler = Update::filter_message()
+        .branch(
+            dptree::entry()
+                .filter_command::<AdminCommand>()
+                .endpoint(handle_command),
+        )
+        .branch(dptree::filter_map(parse_bang_command).endpoint(handle_command))
+        .branch(
+            dptree::filter(|msg: Message, ctx: Arc<AdminContext>| ctx.should_bridge(&msg))
+                .endpoint(handle_bridge),
+        );
+
+    Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![Arc::new(context)])
+        .default_handler(|_| async {})
+        .build()
+        .dispatch()
+        .await;
+    debug!("Admin command daemon exiting...");
+    Ok(())
+}
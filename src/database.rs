@@ -0,0 +1,240 @@
+pub use crate::datastructures::config::SinkPolicy;
+use crate::TelegramData;
+use log::{debug, error, info};
+use rusqlite::{params, Connection, OpenFlags};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// Schema migrations, `PRAGMA user_version` records how many of them are applied.
+const MIGRATIONS: &[&str] = &[
+    r#"
+CREATE TABLE IF NOT EXISTS "events" (
+    "id"                INTEGER PRIMARY KEY AUTOINCREMENT,
+    "timestamp"         INTEGER NOT NULL, -- UTC epoch seconds
+    "event"             TEXT NOT NULL,
+    "client_id"         INTEGER NOT NULL,
+    "unique_identifier" TEXT NOT NULL,
+    "nickname"          TEXT NOT NULL,
+    "country"           TEXT NOT NULL,
+    "reason"            TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS "events_timestamp" ON "events" ("timestamp");
+"#,
+    r#"
+ALTER TABLE "events" ADD COLUMN "client_database_id" INTEGER NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS "events_client_database_id" ON "events" ("client_database_id");
+"#,
+];
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+/// Run `VACUUM` after this many prune rounds (about once a day)
+const VACUUM_EVERY_PRUNE: u32 = 24;
+
+/// Event with optional acknowledge sender, which receives whether the write succeed.
+pub type DatabaseRequest = (TelegramData, Option<oneshot::Sender<bool>>);
+
+/// Latest disconnect of one client
+#[derive(Debug)]
+pub struct LastSeen {
+    pub client_database_id: i64,
+    pub nickname: String,
+    /// UTC epoch seconds
+    pub timestamp: i64,
+}
+
+struct EventLog {
+    conn: Connection,
+}
+
+impl EventLog {
+    fn open(path: &str) -> anyhow::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        Self::migrate(&mut conn)?;
+        Ok(Self { conn })
+    }
+
+    fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (index, statements) in MIGRATIONS.iter().enumerate().skip(version) {
+            let transaction = conn.transaction()?;
+            transaction.execute_batch(statements)?;
+            transaction.pragma_update(None, "user_version", index + 1)?;
+            transaction.commit()?;
+            info!("Database migrated to version {}", index + 1);
+        }
+        Ok(())
+    }
+
+    fn insert(&self, data: &TelegramData) -> rusqlite::Result<()> {
+        let timestamp = match data.time() {
+            Some(time) => time.timestamp(),
+            None => return Ok(()),
+        };
+        match data {
+            TelegramData::Enter(_, _, view, ..) => self.conn.execute(
+                r#"INSERT INTO "events" ("timestamp", "event", "client_id", "client_database_id", "unique_identifier", "nickname", "country", "reason") VALUES (?, 'enter', ?, ?, ?, ?, ?, '')"#,
+                params![
+                    timestamp,
+                    view.client_id(),
+                    view.client_database_id(),
+                    view.client_unique_identifier(),
+                    view.client_nickname(),
+                    view.client_country()
+                ],
+            ),
+            // Left notify does not carry database id, take it from the latest enter of same client id
+            TelegramData::Left(_, _, view, nickname, _) => self.conn.execute(
+                r#"INSERT INTO "events" ("timestamp", "event", "client_id", "client_database_id", "unique_identifier", "nickname", "country", "reason") VALUES (?1, 'left', ?2, COALESCE((SELECT "client_database_id" FROM "events" WHERE "event" = 'enter' AND "client_id" = ?2 ORDER BY "id" DESC LIMIT 1), 0), '', ?3, '', ?4)"#,
+                params![timestamp, view.client_id(), nickname, view.reason()],
+            ),
+            TelegramData::Moved(..)
+            | TelegramData::Renamed(..)
+            | TelegramData::Text(..)
+            | TelegramData::SessionAlert(..)
+            | TelegramData::PacketLoss(..)
+            | TelegramData::Idle(..)
+            | TelegramData::FirstSeen(..)
+            | TelegramData::Digest(..)
+            | TelegramData::Status(..)
+            | TelegramData::ServerStatus(..)
+            | TelegramData::Startup(..)
+            | TelegramData::Shutdown(..)
+            | TelegramData::SessionTerminated(..)
+            | TelegramData::Alert(..)
+            | TelegramData::Terminate => return Ok(()),
+        }
+        .map(|_| ())
+    }
+
+    /// Latest left event of each database id which ever used the nickname or unique identifier
+    fn last_seen(&self, identifier: &str, limit: usize) -> rusqlite::Result<Vec<LastSeen>> {
+        let mut statement = self.conn.prepare(
+            r#"SELECT "client_database_id", "nickname", MAX("timestamp") FROM "events" WHERE "event" = 'left' AND "client_database_id" IN (SELECT "client_database_id" FROM "events" WHERE "client_database_id" > 0 AND ("nickname" = ?1 OR "unique_identifier" = ?1)) GROUP BY "client_database_id" ORDER BY MAX("timestamp") DESC LIMIT ?2"#,
+        )?;
+        let rows = statement.query_map(params![identifier, limit], |row| {
+            Ok(LastSeen {
+                client_database_id: row.get(0)?,
+                nickname: row.get(1)?,
+                timestamp: row.get(2)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    fn prune(&self, retention_days: u64) -> rusqlite::Result<usize> {
+        let before = chrono::Utc::now().timestamp() - (retention_days * 86400) as i64;
+        self.conn.execute(
+            r#"DELETE FROM "events" WHERE "timestamp" < ?"#,
+            params![before],
+        )
+    }
+
+    fn vacuum(&self) -> rusqlite::Result<()> {
+        self.conn.execute_batch("VACUUM")
+    }
+}
+
+/// Look up most recent disconnects by nickname or unique identifier, at most `limit` clients.
+///
+/// Database is opened read only, so it does not block the writer.
+pub fn last_seen(path: &str, identifier: &str, limit: usize) -> anyhow::Result<Vec<LastSeen>> {
+    let log = EventLog {
+        conn: Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?,
+    };
+    Ok(log.last_seen(identifier, limit)?)
+}
+
+/// Write events to SQLite, `retention_days` = 0 means keep everything.
+pub async fn database_thread(
+    path: String,
+    retention_days: u64,
+    mut receiver: mpsc::Receiver<DatabaseRequest>,
+) -> anyhow::Result<()> {
+    let log = EventLog::open(&path)?;
+    let mut prune_timer = tokio::time::interval(PRUNE_INTERVAL);
+    let mut prune_count = 0u32;
+    loop {
+        tokio::select! {
+            cmd = receiver.recv() => {
+                let (cmd, ack) = match cmd {
+                    Some((TelegramData::Terminate, _)) | None => break,
+                    Some(cmd) => cmd,
+                };
+                let result = log.insert(&cmd);
+                if let Err(e) = &result {
+                    error!("Got error while write event to database: {:?}", e);
+                }
+                if let Some(ack) = ack {
+                    ack.send(result.is_ok()).ok();
+                }
+            }
+            _ = prune_timer.tick(), if retention_days > 0 => {
+                match log.prune(retention_days) {
+                    Ok(rows) => info!("Pruned {} event(s) older than {} days", rows, retention_days),
+                    Err(e) => error!("Got error while prune database: {:?}", e),
+                }
+                prune_count += 1;
+                if prune_count % VACUUM_EVERY_PRUNE == 0 {
+                    log.vacuum()
+                        .map_err(|e| error!("Got error while vacuum database: {:?}", e))
+                        .ok();
+                }
+            }
+        }
+    }
+    debug!("Database daemon exiting...");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EventLog, MIGRATIONS};
+    use rusqlite::Connection;
+
+    #[test]
+    fn test_migrate() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        EventLog::migrate(&mut conn).unwrap();
+        // Running again should be no-op
+        EventLog::migrate(&mut conn).unwrap();
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        conn.execute(
+            r#"INSERT INTO "events" ("timestamp", "event", "client_id", "client_database_id", "unique_identifier", "nickname", "country", "reason") VALUES (0, 'enter', 1, 2, '', '', '', '')"#,
+            [],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_last_seen() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        EventLog::migrate(&mut conn).unwrap();
+        conn.execute_batch(
+            r#"INSERT INTO "events" ("timestamp", "event", "client_id", "client_database_id", "unique_identifier", "nickname", "country", "reason") VALUES
+                (10, 'enter', 1, 2, 'alice=', 'alice', '', ''),
+                (20, 'left', 1, 2, '', 'alice', '', ''),
+                (30, 'enter', 3, 4, 'other=', 'alice', '', ''),
+                (40, 'left', 3, 4, '', 'alice', '', ''),
+                (50, 'enter', 5, 2, 'alice=', 'alice2', '', ''),
+                (60, 'left', 5, 2, '', 'alice2', '', '');"#,
+        )
+        .unwrap();
+        let log = EventLog { conn };
+        let seen = log.last_seen("alice", 5).unwrap();
+        assert_eq!(
+            seen.iter()
+                .map(|seen| (seen.client_database_id, seen.timestamp))
+                .collect::<Vec<_>>(),
+            vec![(2, 60), (4, 40)]
+        );
+        let seen = log.last_seen("alice=", 5).unwrap();
+        assert_eq!(seen.len(), 1);
+        // Nickname of the latest disconnect
+        assert_eq!(seen[0].nickname, "alice2");
+        assert_eq!(log.last_seen("alice", 1).unwrap().len(), 1);
+        assert!(log.last_seen("nobody", 5).unwrap().is_empty());
+    }
+}
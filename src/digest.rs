@@ -0,0 +1,74 @@
+use crate::datastructures::ServerInfo;
+use crate::humanize::humanize_duration;
+use std::fmt::Formatter;
+use std::time::{Duration, Instant};
+
+/// Count events between two digests.
+pub struct DigestCounter {
+    interval: Duration,
+    last: Instant,
+    joined: usize,
+    left: usize,
+}
+
+impl DigestCounter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Instant::now(),
+            joined: 0,
+            left: 0,
+        }
+    }
+
+    pub fn record_join(&mut self) {
+        self.joined += 1;
+    }
+    pub fn record_left(&mut self) {
+        self.left += 1;
+    }
+
+    pub fn due(&self) -> bool {
+        self.last.elapsed() >= self.interval
+    }
+
+    /// Take counted events and start next period.
+    pub fn take(&mut self, online: usize, server_info: Option<ServerInfo>) -> DigestSummary {
+        self.last = Instant::now();
+        DigestSummary {
+            joined: std::mem::take(&mut self.joined),
+            left: std::mem::take(&mut self.left),
+            online,
+            server_info,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DigestSummary {
+    joined: usize,
+    left: usize,
+    online: usize,
+    /// `None` if `serverinfo` is not permitted
+    server_info: Option<ServerInfo>,
+}
+
+impl std::fmt::Display for DigestSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "📊 {} joined, {} left, {} online",
+            self.joined, self.left, self.online
+        )?;
+        if let Some(info) = &self.server_info {
+            write!(
+                f,
+                "\nUptime: {}, max clients: {}, reserved slots: {}",
+                humanize_duration(Duration::from_secs(info.uptime())),
+                info.max_clients(),
+                info.reserved_slots()
+            )?;
+        }
+        write!(f, " #digest")
+    }
+}
@@ -0,0 +1,75 @@
+use crate::format::MessageFormat;
+use crate::{display_time, OutputOptions, TelegramData};
+use log::{debug, error, info, warn};
+use serde_json::json;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Discord limits embed description to 4096 characters
+const EMBED_DESCRIPTION_LIMIT: usize = 4096;
+
+/// Discord markdown differs from telegram, so events are rendered as plain text
+const FORMAT: MessageFormat = MessageFormat::None;
+
+pub async fn discord_thread(
+    webhook_url: String,
+    output: OutputOptions,
+    mut receiver: mpsc::Receiver<TelegramData>,
+) -> anyhow::Result<()> {
+    let OutputOptions {
+        sanitize,
+        decoration,
+        server_label,
+        templates,
+        is_leader,
+        status,
+        dry_run,
+    } = output;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    while let Some(cmd) = receiver.recv().await {
+        if let TelegramData::Terminate = cmd {
+            break;
+        }
+        if !is_leader.load(Ordering::Relaxed) {
+            debug!("Standby instance, drop event #{:?}", cmd.sequence());
+            continue;
+        }
+        let line = match &cmd {
+            TelegramData::ServerStatus(..) if !status.announce() => continue,
+            TelegramData::ServerStatus(_, time, _, text) => {
+                format!("[{}] {}", display_time(*time), text)
+            }
+            _ => cmd
+                .sanitized(&sanitize)
+                .render(&templates.read().unwrap(), FORMAT, &server_label),
+        };
+        let line = match &decoration {
+            Some(decoration) => format!("{} {}", decoration, line),
+            None => line,
+        };
+        if dry_run {
+            info!("[dry run] Send discord webhook: {}", line);
+            continue;
+        }
+        let description: String = line.chars().take(EMBED_DESCRIPTION_LIMIT).collect();
+        let body = json!({
+            "embeds": [{
+                "description": description,
+                "timestamp": cmd.time().map(|time| time.to_rfc3339()),
+            }]
+        });
+        match client.post(&webhook_url).json(&body).send().await {
+            Ok(response) if !response.status().is_success() => warn!(
+                "Discord webhook got unexpected status: {}",
+                response.status()
+            ),
+            Ok(_) => {}
+            Err(e) => error!("Got error while send discord webhook: {:?}", e),
+        }
+    }
+    debug!("Discord webhook daemon exiting...");
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use crate::datastructures::{
+    FromQueryString, NotifyClientEnterView, NotifyClientLeftView, NotifyClientMoved,
+    NotifyClientUpdated, NotifyTextMessage,
+};
+use crate::socketlib::SocketConn;
+use std::collections::VecDeque;
+
+/// Parsed server notify.
+#[derive(Clone, Debug)]
+pub enum Event {
+    ClientEnterView(NotifyClientEnterView),
+    ClientLeftView(NotifyClientLeftView),
+    ClientMoved(NotifyClientMoved),
+    ClientUpdated(NotifyClientUpdated),
+    TextMessage(NotifyTextMessage),
+    /// Notify without typed representation (e.g. channel events), kept as raw line
+    Other(String),
+}
+
+impl Event {
+    pub fn parse(line: &str) -> anyhow::Result<Self> {
+        let name = line.split_once(' ').map_or(line, |(name, _)| name);
+        Ok(match name {
+            "notifycliententerview" => {
+                Self::ClientEnterView(NotifyClientEnterView::from_query(line)?)
+            }
+            "notifyclientleftview" => Self::ClientLeftView(NotifyClientLeftView::from_query(line)?),
+            "notifyclientmoved" => Self::ClientMoved(NotifyClientMoved::from_query(line)?),
+            "notifyclientupdated" => Self::ClientUpdated(NotifyClientUpdated::from_query(line)?),
+            "notifytextmessage" => Self::TextMessage(NotifyTextMessage::from_query(line)?),
+            _ => Self::Other(line.to_string()),
+        })
+    }
+}
+
+/// Yield notifies received from logged in connection, command replies are skipped.
+///
+/// Observer binary parses with [`Event::parse`] directly, as it also feeds synthesized lines.
+pub struct EventStream {
+    conn: SocketConn,
+    pending: VecDeque<String>,
+}
+
+impl EventStream {
+    pub fn new(conn: SocketConn) -> Self {
+        Self {
+            conn,
+            pending: Default::default(),
+        }
+    }
+
+    /// Return `None` if nothing received within read timeout of connection,
+    /// caller may send keepalive through [`EventStream::conn_mut`] then.
+    pub async fn next(&mut self) -> anyhow::Result<Option<Event>> {
+        loop {
+            if let Some(line) = self.pending.pop_front() {
+                return Event::parse(&line).map(Some);
+            }
+            match self.conn.read_data().await? {
+                Some(data) => self.pending.extend(
+                    data.lines()
+                        .map(|line| line.trim())
+                        .filter(|line| line.starts_with("notify"))
+                        .map(ToString::to_string),
+                ),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    pub fn conn_mut(&mut self) -> &mut SocketConn {
+        &mut self.conn
+    }
+
+    pub fn into_inner(self) -> SocketConn {
+        self.conn
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Event, EventStream};
+    use crate::socketlib::SocketConn;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        server
+            .write_all(b"TS3\n\rWelcome to the TeamSpeak 3 ServerQuery interface\n\r")
+            .await
+            .unwrap();
+        let conn = SocketConn::from_stream(client).await.unwrap();
+        server
+            .write_all(
+                b"notifycliententerview cfid=0 ctid=1 reasonid=0 clid=5 client_unique_identifier=alice= client_nickname=alice client_country=JP\n\r\
+                  error id=0 msg=ok\n\r\
+                  notifyclientleftview cfid=1 ctid=0 reasonid=8 clid=5\n\r\
+                  notifychanneledited cid=1 reasonid=10\n\r",
+            )
+            .await
+            .unwrap();
+        let mut stream = EventStream::new(conn);
+        assert!(matches!(
+            stream.next().await.unwrap(),
+            Some(Event::ClientEnterView(view)) if view.client_nickname() == "alice"
+        ));
+        assert!(matches!(
+            stream.next().await.unwrap(),
+            Some(Event::ClientLeftView(view)) if view.client_id() == 5
+        ));
+        assert!(matches!(
+            stream.next().await.unwrap(),
+            Some(Event::Other(line)) if line.starts_with("notifychanneledited")
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+use serde_derive::Deserialize;
+use teloxide::types::ParseMode;
+use teloxide::utils::{html, markdown};
+
+/// Message format sent to telegram.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    #[default]
+    Html,
+    /// Telegram MarkdownV2
+    Markdown,
+    /// Plain text, bold and code are dropped
+    None,
+}
+
+impl MessageFormat {
+    pub fn parse_mode(self) -> Option<ParseMode> {
+        match self {
+            MessageFormat::Html => Some(ParseMode::Html),
+            MessageFormat::Markdown => Some(ParseMode::MarkdownV2),
+            MessageFormat::None => None,
+        }
+    }
+
+    pub fn escape(self, s: &str) -> String {
+        match self {
+            MessageFormat::Html => html::escape(s),
+            MessageFormat::Markdown => markdown::escape(s),
+            MessageFormat::None => s.to_string(),
+        }
+    }
+}
+
+/// Build message piece by piece, every piece is escaped for selected format.
+pub struct MessageBuilder {
+    format: MessageFormat,
+    buffer: String,
+}
+
+impl MessageBuilder {
+    pub fn new(format: MessageFormat) -> Self {
+        Self {
+            format,
+            buffer: String::new(),
+        }
+    }
+
+    pub fn text(&mut self, s: &str) -> &mut Self {
+        self.buffer.push_str(&self.format.escape(s));
+        self
+    }
+
+    pub fn bold(&mut self, s: &str) -> &mut Self {
+        let escaped = self.format.escape(s);
+        match self.format {
+            MessageFormat::Html => self.buffer.push_str(&html::bold(&escaped)),
+            MessageFormat::Markdown => self.buffer.push_str(&markdown::bold(&escaped)),
+            MessageFormat::None => self.buffer.push_str(&escaped),
+        }
+        self
+    }
+
+    pub fn code(&mut self, s: &str) -> &mut Self {
+        match self.format {
+            MessageFormat::Html => self.buffer.push_str(&html::code_inline(s)),
+            MessageFormat::Markdown => self.buffer.push_str(&markdown::code_inline(s)),
+            MessageFormat::None => self.buffer.push_str(s),
+        }
+        self
+    }
+
+    pub fn build(&mut self) -> String {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MessageBuilder, MessageFormat};
+
+    #[test]
+    fn test_builder() {
+        let build = |format| {
+            MessageBuilder::new(format)
+                .text("[2022-01-01] ")
+                .bold("a_b<c>")
+                .text("(")
+                .code("uid")
+                .text(") joined")
+                .build()
+        };
+        assert_eq!(
+            build(MessageFormat::Html),
+            "[2022-01-01] <b>a_b&lt;c&gt;</b>(<code>uid</code>) joined"
+        );
+        assert_eq!(
+            build(MessageFormat::Markdown),
+            "\\[2022\\-01\\-01\\] *a\\_b<c\\>*\\(`uid`\\) joined"
+        );
+        assert_eq!(
+            build(MessageFormat::None),
+            "[2022-01-01] a_b<c>(uid) joined"
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use crate::datastructures::Channel;
+use crate::roster::{ChannelMap, ClientMap, OnlineClient};
+use log::{debug, info, warn};
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Serialize)]
+struct ClientEntry<'a> {
+    clid: i64,
+    nickname: &'a str,
+    channel_id: i64,
+    /// Empty if channel is unknown
+    channel: &'a str,
+    /// Empty if unknown
+    country: &'a str,
+}
+
+/// Online clients (query clients excluded) as JSON array, ordered by client id
+fn clients_json(
+    clients: &HashMap<i64, OnlineClient>,
+    channels: &HashMap<i64, Channel>,
+) -> serde_json::Result<String> {
+    let mut entries = clients
+        .iter()
+        .filter(|(_, client)| !client.is_query())
+        .map(|(client_id, client)| ClientEntry {
+            clid: *client_id,
+            nickname: client.nickname(),
+            channel_id: client.channel_id(),
+            channel: channels
+                .get(&client.channel_id())
+                .map_or("", |channel| channel.channel_name()),
+            country: client.country(),
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.clid);
+    serde_json::to_string(&entries)
+}
+
+/// Status, content type and body of response
+pub type Response = (&'static str, String, String);
+
+pub fn not_found() -> Response {
+    (
+        "404 Not Found",
+        String::from("text/plain"),
+        String::from("Not Found\n"),
+    )
+}
+
+async fn handle_request<F, Fut>(route: &F, stream: TcpStream) -> anyhow::Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<Response>>,
+{
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    // Drain headers, request body is never expected
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => route(path.to_string()).await?,
+        _ => not_found(),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.get_mut().write_all(response.as_bytes()).await?;
+    stream.get_mut().shutdown().await?;
+    Ok(())
+}
+
+/// Accept connections on `bind` forever, `route` maps path of GET request to response.
+pub async fn serve<F, Fut>(name: &'static str, bind: String, route: F) -> anyhow::Result<()>
+where
+    F: Fn(String) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<Response>> + Send + 'static,
+{
+    let listener = TcpListener::bind(&bind)
+        .await
+        .map_err(|e| anyhow::anyhow!("Got error while bind {} endpoint: {:?}", name, e))?;
+    info!("{} endpoint listening on {}", name, bind);
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Got error while accept {} connection: {:?}", name, e);
+                continue;
+            }
+        };
+        let route = route.clone();
+        tokio::spawn(async move {
+            let handle = handle_request(&route, stream);
+            match tokio::time::timeout(Duration::from_secs(10), handle).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => debug!("Got error while serve {} to {}: {:?}", name, address, e),
+                Err(_) => debug!("{} request from {} timed out", name, address),
+            }
+        });
+    }
+}
+
+/// Serve online clients of primary server as JSON at `/clients`
+pub async fn server_thread(
+    client_map: ClientMap,
+    channel_map: ChannelMap,
+    bind: String,
+) -> anyhow::Result<()> {
+    serve("HTTP API", bind, move |path| {
+        let client_map = client_map.clone();
+        let channel_map = channel_map.clone();
+        async move {
+            Ok(match path.as_str() {
+                "/clients" => (
+                    "200 OK",
+                    String::from("application/json"),
+                    clients_json(&*client_map.read().await, &*channel_map.read().await)?,
+                ),
+                _ => not_found(),
+            })
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::clients_json;
+    use crate::datastructures::Channel;
+    use crate::roster::OnlineClient;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_clients_json() {
+        let clients = HashMap::from([
+            (
+                7,
+                OnlineClient::new("bob".to_string(), "b".to_string(), 2, false, None),
+            ),
+            (
+                3,
+                OnlineClient::new("alice".to_string(), "a".to_string(), 1, false, None)
+                    .with_country("JP"),
+            ),
+            (
+                1,
+                OnlineClient::new("query".to_string(), "q".to_string(), 1, true, None),
+            ),
+        ]);
+        let channels = HashMap::from([(1, Channel::new(1, 0, "Lobby"))]);
+        assert_eq!(
+            clients_json(&clients, &channels).unwrap(),
+            r#"[{"clid":3,"nickname":"alice","channel_id":1,"channel":"Lobby","country":"JP"},{"clid":7,"nickname":"bob","channel_id":2,"channel":"","country":""}]"#
+        );
+    }
+}
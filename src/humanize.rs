@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+/// Format duration into short human readable text, only keep two most significant units.
+pub fn humanize_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        MINUTE..=3599 => format!("{}m", secs / MINUTE),
+        HOUR..=86399 => {
+            let minutes = (secs % HOUR) / MINUTE;
+            if minutes == 0 {
+                format!("{}h", secs / HOUR)
+            } else {
+                format!("{}h{}m", secs / HOUR, minutes)
+            }
+        }
+        _ => {
+            let hours = (secs % DAY) / HOUR;
+            if hours == 0 {
+                format!("{}d", secs / DAY)
+            } else {
+                format!("{}d{}h", secs / DAY, hours)
+            }
+        }
+    }
+}
+
+/// Format elapsed duration like "just now" or "2m ago".
+pub fn relative_time(elapsed: Duration) -> String {
+    if elapsed.as_secs() < 10 {
+        return "just now".to_string();
+    }
+    format!("{} ago", humanize_duration(elapsed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{humanize_duration, relative_time};
+    use std::time::Duration;
+
+    #[test]
+    fn test_humanize_duration() {
+        assert_eq!(humanize_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(humanize_duration(Duration::from_secs(59)), "59s");
+        assert_eq!(humanize_duration(Duration::from_secs(60)), "1m");
+        assert_eq!(humanize_duration(Duration::from_secs(3599)), "59m");
+        assert_eq!(humanize_duration(Duration::from_secs(3600)), "1h");
+        assert_eq!(
+            humanize_duration(Duration::from_secs(2 * 3600 + 14 * 60)),
+            "2h14m"
+        );
+        assert_eq!(humanize_duration(Duration::from_secs(86399)), "23h59m");
+        assert_eq!(humanize_duration(Duration::from_secs(86400)), "1d");
+        assert_eq!(
+            humanize_duration(Duration::from_secs(3 * 86400 + 7200)),
+            "3d2h"
+        );
+    }
+
+    #[test]
+    fn test_relative_time() {
+        assert_eq!(relative_time(Duration::from_secs(3)), "just now");
+        assert_eq!(relative_time(Duration::from_secs(120)), "2m ago");
+    }
+}
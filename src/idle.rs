@@ -0,0 +1,63 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// Periodically check clients' idle time, warn once per idle session.
+pub struct IdleMonitor {
+    threshold: Duration,
+    interval: Duration,
+    last_check: Instant,
+    warned: HashSet<i64>,
+}
+
+impl IdleMonitor {
+    pub fn new(threshold: Duration, interval: Duration) -> Self {
+        Self {
+            threshold,
+            interval,
+            last_check: Instant::now(),
+            warned: Default::default(),
+        }
+    }
+
+    /// Return true if check interval elapsed since last check, and reset the timer.
+    pub fn due(&mut self) -> bool {
+        if self.last_check.elapsed() < self.interval {
+            return false;
+        }
+        self.last_check = Instant::now();
+        true
+    }
+
+    /// Forget clients which are no longer online.
+    pub fn retain(&mut self, online: impl Fn(i64) -> bool) {
+        self.warned.retain(|client_id| online(*client_id));
+    }
+
+    /// Client becomes active again once idle time drops below threshold, so next idle warns again.
+    pub fn should_warn(&mut self, client_id: i64, idle: Duration) -> bool {
+        if idle < self.threshold {
+            self.warned.remove(&client_id);
+            return false;
+        }
+        self.warned.insert(client_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::IdleMonitor;
+    use std::time::Duration;
+
+    #[test]
+    fn test_should_warn() {
+        let minutes = |m| Duration::from_secs(m * 60);
+        let mut monitor = IdleMonitor::new(minutes(30), minutes(1));
+        assert!(!monitor.should_warn(1, minutes(10)));
+        assert!(monitor.should_warn(1, minutes(31)));
+        assert!(!monitor.should_warn(1, minutes(45)));
+        assert!(!monitor.should_warn(1, minutes(0)));
+        assert!(monitor.should_warn(1, minutes(30)));
+        monitor.retain(|client_id| client_id != 1);
+        assert!(monitor.should_warn(1, minutes(60)));
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::anyhow;
+use fs2::FileExt;
+use log::{error, info, warn};
+use std::fs::OpenOptions;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Elect leader by holding exclusive lock of a shared file.
+///
+/// Lock is released by OS when leader exits, standby instance retries every `interval`
+/// and promotes itself once lock acquired.
+pub async fn leader_thread(
+    path: String,
+    interval: Duration,
+    is_leader: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .open(&path)
+        .map_err(|e| {
+            error!("Got error while open leader lock file: {:?}", e);
+            anyhow!("Got error while open leader lock file: {:?}", e)
+        })?;
+    info!("Running as standby, waiting for lock {}", path);
+    loop {
+        match file.try_lock_exclusive() {
+            Ok(_) => break,
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {}
+            Err(e) => warn!("Got error while acquire leader lock: {:?}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+    is_leader.store(true, Ordering::Relaxed);
+    info!("Acquired leader lock, promoted to leader");
+    // Keep file open to hold the lock until process exits
+    std::future::pending::<()>().await;
+    drop(file);
+    Ok(())
+}
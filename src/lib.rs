@@ -0,0 +1,38 @@
+//! TeamSpeak 3 ServerQuery client, notify parsing and a standalone event stream.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use teamspeak_observer::event::{Event, EventStream};
+//! use teamspeak_observer::socketlib::{EventClass, SocketConn};
+//! use std::time::Duration;
+//!
+//! let mut conn =
+//!     SocketConn::connect("127.0.0.1", 10011, false, Duration::from_secs(10), None).await?;
+//! conn.login("serveradmin", "password").await?;
+//! conn.select_server(1).await?;
+//! conn.register_event(EventClass::Server, None).await?;
+//! let mut stream = EventStream::new(conn);
+//! loop {
+//!     match stream.next().await? {
+//!         Some(Event::ClientEnterView(view)) => println!("{} joined", view.client_nickname()),
+//!         Some(_) => {}
+//!         // Nothing received within read timeout, keep query session alive
+//!         None => {
+//!             stream.conn_mut().who_am_i().await?;
+//!         }
+//!     }
+//! }
+//! # }
+//! ```
+
+pub mod datastructures;
+pub mod event;
+pub mod format;
+pub mod humanize;
+pub mod proxy;
+pub mod quiet;
+pub mod retry;
+pub mod roster;
+pub mod sanitize;
+pub mod socketlib;
+pub mod timefmt;
@@ -1,31 +1,95 @@
-use crate::datastructures::config::Config;
-use crate::datastructures::{FromQueryString, NotifyClientEnterView, NotifyClientLeftView};
-use crate::socketlib::SocketConn;
+use crate::admin::{AdminContext, PauseControl, StaffCommand};
+use crate::database::{DatabaseRequest, SinkPolicy};
+use crate::datastructures::config::{
+    self, Config, EmptyUidPolicy, LogFormat, ObserveMode, RawQuery, Server,
+    ServerStatus as ServerStatusConfig, Telegram,
+};
+use crate::datastructures::{
+    LeftReason, NotifyClientEnterView, NotifyClientLeftView, NotifyClientMoved, NotifyTextMessage,
+    ServerInfo,
+};
+use crate::digest::{DigestCounter, DigestSummary};
+use crate::event::Event;
+use crate::format::{MessageBuilder, MessageFormat};
+use crate::humanize::humanize_duration;
+use crate::idle::IdleMonitor;
+use crate::metrics::Metrics;
+use crate::proxy::Proxy;
+use crate::quality::{PacketLossMonitor, MIN_PACKETS_SENT};
+use crate::quiet::QuietHours;
+use crate::raw_lines::{RawLineBuffer, SharedRawLines};
+use crate::rejoin::{Rejoin, RejoinDebounce};
+use crate::retry::{Backoff, ErrorCategory, RateLimiter};
+use crate::roster::{sessions_of, ChannelLabel, ChannelMap, ClientMap, OnlineClient};
+use crate::sanitize::NicknameSanitize;
+use crate::snapshot::Snapshot;
+use crate::socketlib::{EventClass, SocketConn};
+use crate::template::{Placeholder, SharedTemplates, TemplateKind, Templates};
+use crate::timefmt::TimeDisplay;
+use crate::visitors::KnownVisitors;
 use anyhow::anyhow;
+use chrono::{DateTime, Local, TimeZone, Utc};
 use clap::{arg, Command};
 use log::{debug, error, info, trace, warn, LevelFilter};
 use std::collections::HashMap;
 use std::fmt::Formatter;
 use std::hint::unreachable_unchecked;
+use std::io::Write;
 use std::path::Path;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use teamspeak_observer::{
+    datastructures, event, format, humanize, proxy, quiet, retry, roster, sanitize, socketlib,
+    timefmt,
+};
+use teloxide::payloads::{EditMessageTextSetters, PinChatMessageSetters, SendMessageSetters};
 use teloxide::prelude::*;
-use teloxide::types::ParseMode;
-use tokio::sync::{mpsc, watch, Mutex};
+use teloxide::RequestError;
+use tokio::sync::{mpsc, oneshot, watch, Semaphore};
 
-mod datastructures;
-mod socketlib;
+mod admin;
+mod database;
+mod digest;
+mod discord;
+mod http;
+mod idle;
+mod leader;
+mod matrix;
+mod metrics;
+mod poll;
+mod quality;
+mod raw_lines;
+mod rejoin;
+mod snapshot;
+mod template;
+mod visitors;
+mod webhook;
 
-async fn init_connection(
-    server: String,
-    port: u16,
-    user: &str,
-    password: &str,
-    sid: i64,
-) -> anyhow::Result<SocketConn> {
-    let mut conn = SocketConn::connect(&server, port).await?;
-    conn.login(user, password)
+async fn init_connection(raw_query: &RawQuery, sid: i64) -> anyhow::Result<SocketConn> {
+    let proxy = raw_query.proxy().map(Proxy::parse).transpose()?;
+    let mut conn = SocketConn::connect(
+        &raw_query.server(),
+        raw_query.port(),
+        raw_query.use_ssl(),
+        raw_query.connect_timeout(),
+        proxy,
+    )
+    .await?;
+    conn.set_read_timeout(raw_query.read_timeout());
+    conn.set_command_interval(raw_query.command_interval());
+    if let Some(size) = raw_query.max_buffer_size() {
+        conn.set_max_buffer_size(size);
+    }
+    if let Some(size) = raw_query.read_buffer_size() {
+        conn.set_read_buffer_size(size);
+    }
+    if let Some(label) = raw_query.fallback_encoding() {
+        let encoding = encoding_rs::Encoding::for_label(label.as_bytes())
+            .ok_or_else(|| anyhow!("Unknown fallback encoding: {}", label))?;
+        conn.set_fallback_encoding(Some(encoding));
+    }
+    conn.authenticate(&raw_query.credential())
         .await
         .map_err(|e| anyhow!("Login failed. {:?}", e))?;
 
@@ -36,101 +100,651 @@ async fn init_connection(
     Ok(conn)
 }
 
+/// Monotonically increasing counter of emitted events.
+///
+/// It lives outside staff thread so it survives reconnection, but resets when program restart.
+#[derive(Clone, Debug, Default)]
+struct EventSequence(Arc<AtomicU64>);
+
+impl EventSequence {
+    fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+#[derive(Clone)]
 enum TelegramData {
-    Enter(String, i64, String, String, String),
-    Left(String, NotifyClientLeftView, String),
+    /// Tags are appended by built-in format, badges are also exposed to templates on their own
+    Enter(
+        u64,
+        DateTime<Utc>,
+        NotifyClientEnterView,
+        String,
+        Vec<String>,
+        Vec<String>,
+        ChannelLabel,
+    ),
+    /// With session duration if connect time is known
+    Left(
+        u64,
+        DateTime<Utc>,
+        NotifyClientLeftView,
+        String,
+        Option<Duration>,
+    ),
+    Moved(u64, DateTime<Utc>, NotifyClientMoved, String, ChannelLabel),
+    /// Client id, old nickname and new nickname
+    Renamed(u64, DateTime<Utc>, i64, String, String),
+    /// Chat message with sender nickname
+    Text(u64, DateTime<Utc>, NotifyTextMessage, String),
+    SessionAlert(u64, DateTime<Utc>, String, String, usize),
+    PacketLoss(u64, DateTime<Utc>, i64, String, f64),
+    /// Client id, nickname and idle time
+    Idle(u64, DateTime<Utc>, i64, String, Duration),
+    /// Client id and nickname of client whose database id was never seen before
+    FirstSeen(u64, DateTime<Utc>, i64, String),
+    Digest(u64, DateTime<Utc>, DigestSummary),
+    /// Periodic online count and uptime
+    Status(u64, DateTime<Utc>, ServerInfo),
+    /// Server became reachable (`true`) or lost (`false`), with configured wording
+    ServerStatus(u64, DateTime<Utc>, bool, String),
+    /// Observer started, with server name and server id
+    Startup(u64, DateTime<Utc>, String, i64),
+    /// Observer is exiting cleanly, with server name and server id
+    Shutdown(u64, DateTime<Utc>, String, i64),
+    SessionTerminated(u64, DateTime<Utc>, NotifyClientLeftView),
+    /// Problem of observer itself which needs operator action
+    Alert(u64, DateTime<Utc>, String),
     Terminate,
 }
 
-impl TelegramData {
-    fn from_left(time: String, view: &NotifyClientLeftView, nickname: String) -> Self {
-        Self::Left(time, view.clone(), nickname)
-    }
-    fn from_enter(time: String, view: NotifyClientEnterView) -> Self {
-        Self::Enter(
-            time,
-            view.client_id(),
-            view.client_unique_identifier().to_string(),
-            view.client_nickname().to_string(),
-            view.client_country().to_string(),
-        )
-    }
+/// Observer's own query session was terminated by server admin.
+#[derive(Debug)]
+enum SessionTerminated {
+    Kicked,
+    /// Ban time in seconds, 0 means permanent
+    Banned(u64),
 }
 
-impl std::fmt::Display for TelegramData {
+impl std::fmt::Display for SessionTerminated {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            TelegramData::Enter(time, client_id, client_identifier, nickname, country) => {
-                write!(
-                    f,
-                    "[{}] <b>{}</b>(<code>{}</code>:{})[{}] joined",
+            SessionTerminated::Kicked => write!(f, "Query session was kicked"),
+            SessionTerminated::Banned(time) => {
+                write!(f, "Query session was banned for {} seconds", time)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SessionTerminated {}
+
+impl From<&NotifyClientLeftView> for SessionTerminated {
+    fn from(view: &NotifyClientLeftView) -> Self {
+        if view.reason_id() == 6 {
+            Self::Banned(view.ban_time())
+        } else {
+            Self::Kicked
+        }
+    }
+}
+
+impl TelegramData {
+    fn from_left(
+        seq: u64,
+        time: DateTime<Utc>,
+        view: &NotifyClientLeftView,
+        nickname: String,
+        duration: Option<Duration>,
+    ) -> Self {
+        Self::Left(seq, time, view.clone(), nickname, duration)
+    }
+    fn from_enter(
+        seq: u64,
+        time: DateTime<Utc>,
+        view: NotifyClientEnterView,
+        tags: Vec<String>,
+        badges: Vec<String>,
+        channel: ChannelLabel,
+    ) -> Self {
+        let nickname = view.client_nickname().to_string();
+        Self::Enter(seq, time, view, nickname, tags, badges, channel)
+    }
+    /// Operational events of observer itself, routed to debug chat instead of main chat
+    fn is_diagnostic(&self) -> bool {
+        matches!(
+            self,
+            TelegramData::ServerStatus(..)
+                | TelegramData::Startup(..)
+                | TelegramData::Shutdown(..)
+                | TelegramData::SessionTerminated(..)
+                | TelegramData::Alert(..)
+        )
+    }
+    fn sequence(&self) -> Option<u64> {
+        match self {
+            TelegramData::Enter(seq, ..)
+            | TelegramData::Left(seq, ..)
+            | TelegramData::Moved(seq, ..)
+            | TelegramData::Renamed(seq, ..)
+            | TelegramData::Text(seq, ..)
+            | TelegramData::SessionAlert(seq, ..)
+            | TelegramData::PacketLoss(seq, ..)
+            | TelegramData::Idle(seq, ..)
+            | TelegramData::FirstSeen(seq, ..)
+            | TelegramData::Digest(seq, ..)
+            | TelegramData::Status(seq, ..)
+            | TelegramData::ServerStatus(seq, ..)
+            | TelegramData::Startup(seq, ..)
+            | TelegramData::Shutdown(seq, ..)
+            | TelegramData::SessionTerminated(seq, ..)
+            | TelegramData::Alert(seq, ..) => Some(*seq),
+            TelegramData::Terminate => None,
+        }
+    }
+    /// Time when event happened, used verbatim by structured consumers
+    fn time(&self) -> Option<DateTime<Utc>> {
+        match self {
+            TelegramData::Enter(_, time, ..)
+            | TelegramData::Left(_, time, ..)
+            | TelegramData::Moved(_, time, ..)
+            | TelegramData::Renamed(_, time, ..)
+            | TelegramData::Text(_, time, ..)
+            | TelegramData::SessionAlert(_, time, ..)
+            | TelegramData::PacketLoss(_, time, ..)
+            | TelegramData::Idle(_, time, ..)
+            | TelegramData::FirstSeen(_, time, ..)
+            | TelegramData::Digest(_, time, ..)
+            | TelegramData::Status(_, time, ..)
+            | TelegramData::ServerStatus(_, time, ..)
+            | TelegramData::Startup(_, time, ..)
+            | TelegramData::Shutdown(_, time, ..)
+            | TelegramData::SessionTerminated(_, time, ..)
+            | TelegramData::Alert(_, time, ..) => Some(*time),
+            TelegramData::Terminate => None,
+        }
+    }
+    /// Replace displayed nickname with sanitized one, raw nickname is kept in view.
+    fn sanitized(self, sanitize: &NicknameSanitize) -> Self {
+        match self {
+            TelegramData::Enter(seq, time, view, nickname, tags, badges, channel) => {
+                TelegramData::Enter(
+                    seq,
+                    time,
+                    view,
+                    sanitize.apply(&nickname),
+                    tags,
+                    badges,
+                    channel,
+                )
+            }
+            TelegramData::Left(seq, time, view, nickname, duration) => {
+                TelegramData::Left(seq, time, view, sanitize.apply(&nickname), duration)
+            }
+            TelegramData::Moved(seq, time, view, nickname, channel) => {
+                TelegramData::Moved(seq, time, view, sanitize.apply(&nickname), channel)
+            }
+            TelegramData::Renamed(seq, time, client_id, old, new) => TelegramData::Renamed(
+                seq,
+                time,
+                client_id,
+                sanitize.apply(&old),
+                sanitize.apply(&new),
+            ),
+            TelegramData::Text(seq, time, view, nickname) => {
+                TelegramData::Text(seq, time, view, sanitize.apply(&nickname))
+            }
+            TelegramData::SessionAlert(seq, time, unique_identifier, nickname, count) => {
+                TelegramData::SessionAlert(
+                    seq,
+                    time,
+                    unique_identifier,
+                    sanitize.apply(&nickname),
+                    count,
+                )
+            }
+            TelegramData::PacketLoss(seq, time, client_id, nickname, packet_loss) => {
+                TelegramData::PacketLoss(
+                    seq,
                     time,
-                    nickname,
-                    client_identifier,
                     client_id,
-                    country_emoji::flag(country).unwrap_or_else(|| country.to_string())
+                    sanitize.apply(&nickname),
+                    packet_loss,
                 )
             }
-            TelegramData::Left(time, view, nickname) => match view.reason_id() {
-                8 => {
-                    if view.reason().is_empty() {
-                        write!(
-                            f,
-                            "[{}] <b>{}</b>({}) left",
-                            time,
-                            nickname,
-                            view.client_id()
-                        )
-                    } else {
-                        write!(
-                            f,
-                            "[{}] <b>{}</b>({}) left ({})",
-                            time,
-                            nickname,
-                            view.client_id(),
-                            view.reason()
-                        )
+            TelegramData::Idle(seq, time, client_id, nickname, idle) => {
+                TelegramData::Idle(seq, time, client_id, sanitize.apply(&nickname), idle)
+            }
+            TelegramData::FirstSeen(seq, time, client_id, nickname) => {
+                TelegramData::FirstSeen(seq, time, client_id, sanitize.apply(&nickname))
+            }
+            TelegramData::Digest(..)
+            | TelegramData::Status(..)
+            | TelegramData::ServerStatus(..)
+            | TelegramData::Startup(..)
+            | TelegramData::Shutdown(..)
+            | TelegramData::SessionTerminated(..)
+            | TelegramData::Alert(..)
+            | TelegramData::Terminate => self,
+        }
+    }
+}
+
+impl TelegramData {
+    /// Format with user defined template if exists, otherwise built-in format is used.
+    fn render(&self, templates: &Templates, format: MessageFormat, server_label: &str) -> String {
+        let time = self.time().map(display_time).unwrap_or_default();
+        let (kind, value): (TemplateKind, Box<dyn Fn(Placeholder) -> String + '_>) = match self {
+            TelegramData::Enter(_, _, view, nickname, _, badges, channel) => (
+                TemplateKind::Enter,
+                Box::new(move |placeholder| match placeholder {
+                    Placeholder::Time => time.clone(),
+                    Placeholder::Nickname => nickname.clone(),
+                    Placeholder::Uid => view.client_unique_identifier().to_string(),
+                    Placeholder::Clid => view.client_id().to_string(),
+                    Placeholder::Country => view.client_country().to_string(),
+                    Placeholder::Flag => country_emoji::flag(view.client_country())
+                        .unwrap_or_else(|| view.client_country().to_string()),
+                    Placeholder::Reason | Placeholder::Departure => String::new(),
+                    Placeholder::ChannelName => channel.name().to_string(),
+                    Placeholder::ChannelPath => channel.path().to_string(),
+                    Placeholder::Platform => view.client_platform().to_string(),
+                    Placeholder::Version => view.client_version().to_string(),
+                    Placeholder::Duration => String::new(),
+                    Placeholder::ServerLabel => server_label.to_string(),
+                    Placeholder::Badges => badges.join(", "),
+                }),
+            ),
+            TelegramData::Left(_, _, view, nickname, duration) => (
+                TemplateKind::Left,
+                Box::new(move |placeholder| match placeholder {
+                    Placeholder::Time => time.clone(),
+                    Placeholder::Nickname => nickname.clone(),
+                    Placeholder::Clid => view.client_id().to_string(),
+                    Placeholder::Reason => view.reason().to_string(),
+                    Placeholder::Departure => view.left_reason().label().to_string(),
+                    Placeholder::Duration => duration.map(humanize_duration).unwrap_or_default(),
+                    Placeholder::ServerLabel => server_label.to_string(),
+                    Placeholder::Uid
+                    | Placeholder::Country
+                    | Placeholder::Flag
+                    | Placeholder::Badges
+                    | Placeholder::ChannelName
+                    | Placeholder::ChannelPath
+                    | Placeholder::Platform
+                    | Placeholder::Version => String::new(),
+                }),
+            ),
+            _ => return self.format(format),
+        };
+        templates
+            .select(kind, &*value)
+            .map(|template| template.render(&|placeholder| format.escape(&value(placeholder))))
+            .unwrap_or_else(|| self.format(format))
+    }
+}
+
+/// Set once from configure file before any message is formatted
+static TIME_DISPLAY: OnceLock<TimeDisplay> = OnceLock::new();
+
+fn display_time(time: DateTime<Utc>) -> String {
+    TIME_DISPLAY.get_or_init(TimeDisplay::default).display(time)
+}
+
+impl TelegramData {
+    /// Format built-in message, user provided text is escaped according to `format`.
+    fn format(&self, format: MessageFormat) -> String {
+        let time = format!("[{}] ", self.time().map(display_time).unwrap_or_default());
+        let mut m = MessageBuilder::new(format);
+        m.text(&time);
+        match self {
+            TelegramData::Enter(_, _, view, nickname, tags, _, channel) => {
+                let country = view.client_country();
+                m.bold(nickname)
+                    .text("(")
+                    .code(&view.identity())
+                    .text(&format!(
+                        ":{})[{}] joined in {}",
+                        view.client_id(),
+                        country_emoji::flag(country).unwrap_or_else(|| country.to_string()),
+                        if channel.show_path() {
+                            channel.path()
+                        } else {
+                            channel.name()
+                        }
+                    ));
+                if !tags.is_empty() {
+                    m.text(&format!(" {}", tags.join(", ")));
+                }
+            }
+            TelegramData::Left(_, _, view, nickname, duration) => {
+                m.bold(nickname).text(&format!("({})", view.client_id()));
+                let after = duration
+                    .map(|duration| format!(" after {}", humanize_duration(duration)))
+                    .unwrap_or_default();
+                match view.left_reason() {
+                    reason @ (LeftReason::Kicked | LeftReason::Banned) => {
+                        m.text(&format!(
+                            " was #{} by ",
+                            if reason == LeftReason::Kicked {
+                                "kicked"
+                            } else {
+                                "banned"
+                            }
+                        ))
+                        .bold(view.invoker_name())
+                        .text("(")
+                        .code(view.invoker_uid())
+                        .text(")");
+                        if view.reason().is_empty() {
+                            m.text(" with no reason");
+                        } else {
+                            m.text(&format!(": {}", view.reason()));
+                        }
+                    }
+                    LeftReason::TimedOut => {
+                        m.text(&format!(" timed out{} #timeout", after));
+                    }
+                    reason => {
+                        m.text(&format!(" {}{}", reason.label(), after));
+                        if !view.reason().is_empty() {
+                            m.text(&format!(" ({})", view.reason()));
+                        }
                     }
                 }
-                3 => write!(
-                    f,
-                    "[{}] <b>{}</b>({}) connection lost #timeout",
-                    time,
-                    nickname,
-                    view.client_id()
-                ),
-                5 | 6 => {
-                    write!(f,
-                           "[{time}] <b>{nickname}</b>({client_id}) was #{operation} by <b>{invoker}</b>(<code>{invoker_uid}</code>){reason}",
-                           time = time,
-                           nickname = nickname,
-                           operation = if view.reason_id() == 5 { "kicked" } else { "banned" },
-                           client_id = view.client_id(),
-                           invoker = view.invoker_name(),
-                           invoker_uid = view.invoker_uid(),
-                           reason = if view.reason().is_empty() {
-                               " with no reason".to_string()
-                           } else {
-                               format!(": {}", view.reason())
-                           }
-                    )
+            }
+            TelegramData::Moved(_, _, view, nickname, channel) => {
+                let target = if channel.show_path() {
+                    channel.path()
+                } else {
+                    channel.name()
+                };
+                m.bold(nickname).text(&format!("({})", view.client_id()));
+                match view.reason_id() {
+                    1 => m
+                        .text(&format!(" was moved to {} by ", target))
+                        .bold(view.invoker_name()),
+                    4 => m
+                        .text(" was kicked from channel by ")
+                        .bold(view.invoker_name()),
+                    _ => m.text(&format!(" moved to {}", target)),
+                };
+            }
+            TelegramData::Renamed(_, _, client_id, old, new) => {
+                m.bold(old)
+                    .text(&format!("({}) renamed to ", client_id))
+                    .bold(new);
+            }
+            TelegramData::Text(_, _, view, nickname) => {
+                m.text(match view.target_mode() {
+                    1 => "✉️ ",
+                    2 => "💬 [channel] ",
+                    _ => "💬 ",
+                })
+                .bold(nickname)
+                .text(&format!(": {}", view.msg()));
+            }
+            TelegramData::SessionAlert(_, _, unique_identifier, nickname, count) => {
+                m.text("⚠️ ")
+                    .code(unique_identifier)
+                    .text("(")
+                    .bold(nickname)
+                    .text(&format!(
+                        ") has {} simultaneous sessions #multisession",
+                        count
+                    ));
+            }
+            TelegramData::PacketLoss(_, _, client_id, nickname, packet_loss) => {
+                m.text("📶 ").bold(nickname).text(&format!(
+                    "({}) has {:.1}% packet loss #packetloss",
+                    client_id, packet_loss
+                ));
+            }
+            TelegramData::Idle(_, _, client_id, nickname, idle) => {
+                m.text("💤 ").bold(nickname).text(&format!(
+                    "({}) has been idle for {} #idle",
+                    client_id,
+                    humanize_duration(*idle)
+                ));
+            }
+            TelegramData::FirstSeen(_, _, client_id, nickname) => {
+                m.text("🆕 ").bold(nickname).text(&format!(
+                    "({}) connected for the first time #newvisitor",
+                    client_id
+                ));
+            }
+            TelegramData::Digest(_, _, summary) => {
+                m.text(&summary.to_string());
+            }
+            TelegramData::Status(_, _, info) => {
+                m.text(&format!(
+                    "💓 {} clients online, server up {} #status",
+                    info.clients_online(),
+                    humanize_duration(Duration::from_secs(info.uptime()))
+                ));
+            }
+            TelegramData::ServerStatus(_, _, _, text) => {
+                m.text(text);
+            }
+            TelegramData::Startup(_, _, name, server_id) => {
+                m.text("🚀 Observer connected to ")
+                    .bold(name)
+                    .text(&format!(" (sid {})", server_id));
+            }
+            TelegramData::Shutdown(_, _, name, server_id) => {
+                m.text("👋 Observer disconnecting from ")
+                    .bold(name)
+                    .text(&format!(" (sid {})", server_id));
+            }
+            TelegramData::SessionTerminated(_, _, view) => {
+                m.text(&format!(
+                    "⛔ Observer's query session was #{} by ",
+                    if view.reason_id() == 6 {
+                        "banned"
+                    } else {
+                        "kicked"
+                    }
+                ))
+                .bold(view.invoker_name())
+                .text("(")
+                .code(view.invoker_uid())
+                .text(")");
+                if !view.reason().is_empty() {
+                    m.text(&format!(": {}", view.reason()));
                 }
-                _ => unreachable!("Got unexpected left message: {:?}", view),
-            },
+            }
+            TelegramData::Alert(_, _, message) => {
+                m.text("⚠️ ").text(message).text(" #alert");
+            }
             TelegramData::Terminate => unsafe {
                 unreachable_unchecked();
             },
         }
+        m.build()
     }
 }
 
-async fn telegram_thread(
+/// Fan out events to every enabled consumer.
+#[derive(Clone)]
+struct EventSender {
+    outputs: Vec<mpsc::Sender<TelegramData>>,
+    database: Option<mpsc::Sender<DatabaseRequest>>,
+    policy: SinkPolicy,
+}
+
+impl EventSender {
+    async fn send(&self, data: TelegramData) {
+        // Database is shared by every server, it stops once all senders are dropped
+        if let Some(database) = self
+            .database
+            .as_ref()
+            .filter(|_| !matches!(data, TelegramData::Terminate))
+        {
+            let (ack, receiver) = match self.policy {
+                SinkPolicy::DurableFirst => {
+                    let (sender, receiver) = oneshot::channel();
+                    (Some(sender), Some(receiver))
+                }
+                _ => (None, None),
+            };
+            if database.send((data.clone(), ack)).await.is_err() {
+                error!("Got error while send data to database");
+            } else if let Some(receiver) = receiver {
+                if !receiver.await.unwrap_or(false) {
+                    warn!("Event is not written to database, send to outputs anyway");
+                }
+            }
+        }
+        for output in &self.outputs {
+            output
+                .send(data.clone())
+                .await
+                .map_err(|_| error!("Got error while send data to output"))
+                .ok();
+        }
+    }
+}
+
+const TELEGRAM_MESSAGE_LIMIT: usize = 4096;
+
+/// Join formatted events into as few messages as possible without exceeding length limit.
+fn join_messages(lines: Vec<String>) -> Vec<String> {
+    let mut messages: Vec<String> = Vec::new();
+    for line in lines {
+        match messages.last_mut() {
+            Some(current) if current.len() + line.len() + 1 <= TELEGRAM_MESSAGE_LIMIT => {
+                current.push('\n');
+                current.push_str(&line);
+            }
+            _ => messages.push(line),
+        }
+    }
+    messages
+}
+
+/// Take events already queued without waiting.
+///
+/// Return `true` if terminate signal received.
+fn drain_queued(
+    receiver: &mut mpsc::Receiver<TelegramData>,
+    batch: &mut Vec<TelegramData>,
+) -> bool {
+    while let Ok(cmd) = receiver.try_recv() {
+        if let TelegramData::Terminate = cmd {
+            return true;
+        }
+        batch.push(cmd);
+    }
+    false
+}
+
+/// Wait more events until `idle` passed without new event or `window` elapsed.
+///
+/// Return `true` if terminate signal received.
+async fn collect_batch(
+    receiver: &mut mpsc::Receiver<TelegramData>,
+    batch: &mut Vec<TelegramData>,
+    window: Duration,
+    idle: Duration,
+) -> bool {
+    let deadline = tokio::time::Instant::now() + window;
+    loop {
+        let idle_deadline = std::cmp::min(tokio::time::Instant::now() + idle, deadline);
+        match tokio::time::timeout_at(idle_deadline, receiver.recv()).await {
+            Ok(Some(TelegramData::Terminate)) | Ok(None) => return true,
+            Ok(Some(cmd)) => batch.push(cmd),
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Rendering settings shared by every output of one server.
+#[derive(Clone)]
+struct OutputOptions {
+    sanitize: NicknameSanitize,
+    decoration: Option<String>,
+    server_label: String,
+    templates: SharedTemplates,
+    is_leader: Arc<AtomicBool>,
+    status: ServerStatusConfig,
+    dry_run: bool,
+}
+
+impl OutputOptions {
+    fn new(
+        config: &Config,
+        server: &Server,
+        templates: SharedTemplates,
+        is_leader: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            sanitize: config.misc().nickname_sanitize().clone(),
+            decoration: server.decoration(),
+            server_label: server.label().unwrap_or_default().to_string(),
+            templates,
+            is_leader,
+            status: config.status().clone(),
+            dry_run: config.misc().dry_run(),
+        }
+    }
+}
+
+/// Telegram specific settings of one server.
+struct TelegramOptions {
     token: String,
-    target: i64,
+    targets: Vec<i64>,
+    debug_targets: Option<Vec<i64>>,
     server: String,
+    batch_window: Duration,
+    batch_idle: Duration,
+    format: MessageFormat,
+    limiter: RateLimiter,
+    quiet_hours: Option<QuietHours>,
+}
+
+impl TelegramOptions {
+    fn new(telegram: &Telegram, server: &Server, quiet_hours: Option<QuietHours>) -> Self {
+        Self {
+            token: telegram.api_key().to_string(),
+            targets: server.targets().unwrap_or_else(|| telegram.targets()),
+            debug_targets: telegram.debug_targets(),
+            server: telegram.api_server(),
+            batch_window: telegram.batch_window(),
+            batch_idle: telegram.batch_idle(),
+            format: telegram.parse_mode(),
+            limiter: RateLimiter::new(telegram.max_messages_per_second()),
+            quiet_hours,
+        }
+    }
+}
+
+async fn telegram_thread(
+    options: TelegramOptions,
+    output: OutputOptions,
+    metrics: Arc<Metrics>,
     mut receiver: mpsc::Receiver<TelegramData>,
 ) -> anyhow::Result<()> {
-    if token.is_empty() {
+    let TelegramOptions {
+        token,
+        targets,
+        debug_targets,
+        server,
+        batch_window,
+        batch_idle,
+        format,
+        mut limiter,
+        quiet_hours,
+    } = options;
+    let OutputOptions {
+        sanitize,
+        decoration,
+        server_label,
+        templates,
+        is_leader,
+        status,
+        dry_run,
+    } = output;
+    if token.is_empty() && !dry_run {
         warn!("Token is empty, skipped all send message request.");
         while let Some(cmd) = receiver.recv().await {
             if let TelegramData::Terminate = cmd {
@@ -141,175 +755,1704 @@ async fn telegram_thread(
     }
     let bot = Bot::new(token).set_api_url(server.parse()?);
 
-    let bot = bot.parse_mode(ParseMode::Html);
+    let mut pinned_status = HashMap::new();
     while let Some(cmd) = receiver.recv().await {
         if let TelegramData::Terminate = cmd {
             break;
         }
-        let payload = bot.send_message(ChatId(target), cmd.to_string());
-        if let Err(e) = payload.send().await {
-            error!("Got error in send message {:?}", e);
+        let mut batch = vec![cmd];
+        // Events piled up while previous message was sending are always merged
+        let terminate = drain_queued(&mut receiver, &mut batch)
+            || (!batch_window.is_zero()
+                && collect_batch(&mut receiver, &mut batch, batch_window, batch_idle).await);
+        if !is_leader.load(Ordering::Relaxed) {
+            debug!("Standby instance, drop {} event(s)", batch.len());
+            if terminate {
+                break;
+            }
+            continue;
+        }
+        if quiet_hours.map_or(false, |quiet| quiet.is_quiet_now()) {
+            // Editing pinned status does not push notification, keep it up to date
+            let before = batch.len();
+            batch.retain(|cmd| {
+                status.edit_pinned() && matches!(cmd, TelegramData::ServerStatus(..))
+            });
+            debug!("Quiet hours, suppress {} event(s)", before - batch.len());
+        }
+        debug!(
+            "Sending event #{:?}",
+            batch.iter().map(|cmd| cmd.sequence()).collect::<Vec<_>>()
+        );
+        let mut status_line = None;
+        let lines = batch
+            .into_iter()
+            .filter(|cmd| status.announce() || !matches!(cmd, TelegramData::ServerStatus(..)))
+            .filter_map(|cmd| {
+                let is_status = matches!(cmd, TelegramData::ServerStatus(..));
+                let is_diagnostic = cmd.is_diagnostic();
+                let line = match &cmd {
+                    TelegramData::ServerStatus(_, time, _, text) => format!(
+                        "{} {}",
+                        format.escape(&format!("[{}]", display_time(*time))),
+                        text
+                    ),
+                    _ => cmd.sanitized(&sanitize).render(
+                        &templates.read().unwrap(),
+                        format,
+                        &server_label,
+                    ),
+                };
+                let line = match &decoration {
+                    Some(decoration) => format!("{} {}", decoration, line),
+                    None => line,
+                };
+                if is_status && status.edit_pinned() {
+                    status_line.replace(line);
+                    return None;
+                }
+                Some((is_diagnostic, line))
+            })
+            .collect::<Vec<_>>();
+        let (debug_lines, lines): (Vec<_>, Vec<_>) = lines
+            .into_iter()
+            .partition(|(is_diagnostic, _)| *is_diagnostic);
+        let lines = lines.into_iter().map(|(_, line)| line).collect();
+        let debug_lines = debug_lines
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect::<Vec<_>>();
+        let debug_targets = match &debug_targets {
+            Some(debug_targets) => debug_targets.as_slice(),
+            None => {
+                for line in &debug_lines {
+                    info!("Diagnostic event: {}", line);
+                }
+                &[]
+            }
+        };
+        if dry_run {
+            for line in status_line.iter() {
+                info!("[dry run] Update pinned status in {:?}: {}", targets, line);
+            }
+            for message in join_messages(lines) {
+                info!("[dry run] Send message to {:?}: {}", targets, message);
+            }
+            if !debug_targets.is_empty() {
+                for message in join_messages(debug_lines) {
+                    info!("[dry run] Send message to {:?}: {}", debug_targets, message);
+                }
+            }
+            if terminate {
+                break;
+            }
+            continue;
+        }
+        if let Some(line) = status_line {
+            for target in &targets {
+                limiter.wait().await;
+                update_pinned_status(
+                    &bot,
+                    *target,
+                    format,
+                    pinned_status.entry(*target).or_default(),
+                    line.clone(),
+                )
+                .await
+                .map_err(|e| {
+                    metrics.telegram_send_failures().inc();
+                    error!(
+                        "Got error while update pinned status in {}: {:?}",
+                        target, e
+                    )
+                })
+                .ok();
+            }
+        }
+        for message in join_messages(lines) {
+            // Each chat is sent independently, one failed chat does not affect others
+            for target in &targets {
+                if let Err(e) = send_with_retry(&bot, *target, &message, format, &mut limiter).await
+                {
+                    metrics.telegram_send_failures().inc();
+                    error!("Got error in send message to {}: {:?}", target, e);
+                }
+            }
+        }
+        if !debug_targets.is_empty() {
+            for message in join_messages(debug_lines) {
+                for target in debug_targets {
+                    if let Err(e) =
+                        send_with_retry(&bot, *target, &message, format, &mut limiter).await
+                    {
+                        metrics.telegram_send_failures().inc();
+                        error!("Got error in send message to {}: {:?}", target, e);
+                    }
+                }
+            }
+        }
+        if terminate {
+            break;
         }
     }
     debug!("Send message daemon exiting...");
     Ok(())
 }
 
-async fn staff_thread(
-    mut conn: SocketConn,
-    mut recv: watch::Receiver<bool>,
-    sender: mpsc::Sender<TelegramData>,
-    interval: u64,
-    notify_signal: Arc<Mutex<bool>>,
-    ignore_list: Vec<String>,
+const MAX_RATE_LIMIT_RETRY: usize = 5;
+
+/// Send message, wait and retry if telegram replies 429 Too Many Requests.
+async fn send_with_retry(
+    bot: &Bot,
+    target: i64,
+    text: &str,
+    format: MessageFormat,
+    limiter: &mut RateLimiter,
+) -> Result<Message, RequestError> {
+    let mut retry = 0;
+    loop {
+        limiter.wait().await;
+        let mut payload = bot.send_message(ChatId(target), text.to_string());
+        if let Some(parse_mode) = format.parse_mode() {
+            payload = payload.parse_mode(parse_mode);
+        }
+        match payload.send().await {
+            Err(RequestError::RetryAfter(duration)) if retry < MAX_RATE_LIMIT_RETRY => {
+                retry += 1;
+                warn!("Rate limited by telegram, retry after {:?}", duration);
+                tokio::time::sleep(duration).await;
+            }
+            ret => return ret,
+        }
+    }
+}
+
+/// Edit pinned status message, send and pin a new one at first time or if edit failed.
+async fn update_pinned_status(
+    bot: &Bot,
+    target: i64,
+    format: MessageFormat,
+    pinned: &mut Option<Message>,
+    text: String,
 ) -> anyhow::Result<()> {
-    let mut client_map: HashMap<i64, (String, bool)> = HashMap::new();
-    for client in conn
-        .query_clients()
+    if let Some(message) = pinned {
+        let mut payload = bot.edit_message_text(ChatId(target), message.id, text.clone());
+        if let Some(parse_mode) = format.parse_mode() {
+            payload = payload.parse_mode(parse_mode);
+        }
+        match payload.send().await {
+            Ok(_) => return Ok(()),
+            Err(e) => warn!("Got error while edit status message, send new one: {:?}", e),
+        }
+    }
+    let mut payload = bot.send_message(ChatId(target), text);
+    if let Some(parse_mode) = format.parse_mode() {
+        payload = payload.parse_mode(parse_mode);
+    }
+    let message = payload.send().await?;
+    bot.pin_chat_message(ChatId(target), message.id)
+        .disable_notification(true)
+        .send()
+        .await?;
+    pinned.replace(message);
+    Ok(())
+}
+
+async fn load_channels(conn: &mut SocketConn, channel_map: &ChannelMap) -> anyhow::Result<()> {
+    let channels = conn
+        .query_channels()
         .await
-        .map_err(|e| anyhow!("QueryClient failure: {:?}", e))?
+        .map_err(|e| anyhow!("QueryChannel failure: {:?}", e))?;
+    let mut channel_map = channel_map.write().await;
+    channel_map.clear();
+    for channel in channels {
+        channel_map.insert(channel.channel_id(), channel);
+    }
+    Ok(())
+}
+
+/// Fill channel and client cache from server, previous entries are dropped.
+async fn load_server_state(
+    conn: &mut SocketConn,
+    client_map: &ClientMap,
+    channel_map: &ChannelMap,
+    ignore_list: &[String],
+) -> anyhow::Result<()> {
+    load_channels(conn, channel_map).await?;
+
     {
-        if client_map.get(&client.client_id()).is_some() || client.client_type() == 1 {
-            continue;
+        let mut client_map = client_map.write().await;
+        client_map.clear();
+        for client in conn
+            .query_clients()
+            .await
+            .map_err(|e| anyhow!("QueryClient failure: {:?}", e))?
+        {
+            if client_map.get(&client.client_id()).is_some() || client.client_type() == 1 {
+                continue;
+            }
+
+            let is_ignored = ignore_list
+                .iter()
+                .any(|element| element.eq(client.client_unique_identifier()));
+            client_map.insert(
+                client.client_id(),
+                OnlineClient::new(
+                    client.client_nickname().to_string(),
+                    client.client_unique_identifier().to_string(),
+                    client.channel_id(),
+                    is_ignored,
+                    client.last_connected(),
+                )
+                .with_database_id(client.client_database_id())
+                .with_server_groups(client.server_groups())
+                .with_country(client.client_country()),
+            );
         }
+    }
+    Ok(())
+}
 
-        client_map.insert(
-            client.client_id(),
-            (client.client_nickname().to_string(), false),
-        );
+/// Reconnect until succeed, return `false` if exit signal received while waiting.
+///
+/// Session was established before, so every failure is retried (server may be still starting).
+async fn reconnect_with_backoff(
+    conn: &mut SocketConn,
+    recv: &mut watch::Receiver<bool>,
+    backoff: &mut Backoff,
+) -> bool {
+    backoff.reset();
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let delay = backoff.next_delay();
+        info!("Reconnect attempt {} in {:?}", attempt, delay);
+        if tokio::time::timeout(delay, recv.changed()).await.is_ok() {
+            return false;
+        }
+        match conn.reconnect().await {
+            Ok(_) => {
+                info!("Reconnected after {} attempt(s)", attempt);
+                return true;
+            }
+            Err(e) => warn!(
+                "Reconnect failed ({:?}): {:?}",
+                ErrorCategory::classify(&e),
+                e
+            ),
+        }
     }
+}
 
-    conn.register_events()
-        .await
-        .map_err(|e| anyhow!("Got error while register events: {:?}", e))?;
+/// Connection for on-demand queries, the event connection is used if no dedicated one
+fn query_conn<'a>(
+    conn: &'a mut SocketConn,
+    command_conn: &'a mut Option<SocketConn>,
+) -> &'a mut SocketConn {
+    command_conn.as_mut().unwrap_or(conn)
+}
 
-    let mut received = true;
-    debug!("Loop running!");
+/// Probe dedicated command connection, reconnect it if it does not answer.
+async fn keep_command_conn(conn: &mut SocketConn, timeout: Duration) {
+    match tokio::time::timeout(timeout, conn.who_am_i()).await {
+        Ok(Ok(_)) => return,
+        Ok(Err(e)) => warn!("Got error in command connection keepalive: {:?}", e),
+        Err(_) => warn!(
+            "Command connection keepalive is not answered within {:?}",
+            timeout
+        ),
+    }
+    match conn.reconnect().await {
+        Ok(_) => info!("Command connection reconnected"),
+        Err(e) => warn!("Got error while reconnect command connection: {:?}", e),
+    }
+}
 
-    loop {
-        if recv
-            .has_changed()
-            .map_err(|e| anyhow!("Got error in check watcher {:?}", e))?
-        {
-            info!("Exit from staff thread!");
-            conn.logout().await.ok();
-            break;
+/// Announce shutdown (once, if lifecycle announcement enabled) and log out both connections.
+async fn shutdown_staff(
+    conn: &mut SocketConn,
+    command_conn: &mut Option<SocketConn>,
+    sender: &EventSender,
+    sequence: &EventSequence,
+    server_name: &mut Option<String>,
+) {
+    info!("Exit from staff thread!");
+    if let Some(server_name) = server_name.take() {
+        sender
+            .send(TelegramData::Shutdown(
+                sequence.next(),
+                Utc::now(),
+                server_name,
+                conn.server_id().unwrap_or_default(),
+            ))
+            .await;
+    }
+    conn.logout().await.ok();
+    if let Some(command_conn) = command_conn.as_mut() {
+        command_conn.logout().await.ok();
+    }
+}
+
+/// Per-server settings and monitors of staff thread, built once from configure file.
+struct StaffOptions {
+    interval: u64,
+    keepalive: (Duration, Duration),
+    ignore_list: Vec<String>,
+    watch_list: Vec<String>,
+    watch_channels: Vec<i64>,
+    ignore_servergroups: Vec<i64>,
+    watch_servergroups: Vec<i64>,
+    show_badges: bool,
+    events: Vec<(EventClass, Option<i64>)>,
+    max_sessions_per_uid: Option<usize>,
+    enrichment_limit: Arc<Semaphore>,
+    packet_loss_monitor: Option<PacketLossMonitor>,
+    idle_monitor: Option<IdleMonitor>,
+    digest: Option<DigestCounter>,
+    show_channel_path: bool,
+    empty_uid_policy: EmptyUidPolicy,
+    reconnect: Option<Backoff>,
+    notify_move: bool,
+    show_platform: bool,
+    rejoin: Option<RejoinDebounce<TelegramData>>,
+    announce_lifecycle: bool,
+    show_ip: bool,
+    notify_rename: bool,
+    state_file: Option<String>,
+    alert_permission_error: bool,
+    mode: ObserveMode,
+    visitors: Option<KnownVisitors>,
+    status: ServerStatusConfig,
+}
+
+impl StaffOptions {
+    fn new(config: &Config, server: &Server) -> anyhow::Result<Self> {
+        let misc = config.misc();
+        Ok(Self {
+            interval: misc.interval(),
+            keepalive: (misc.keepalive_idle(), misc.keepalive_timeout()),
+            ignore_list: server.ignore_user_name(),
+            watch_list: server.watch_user().clone(),
+            watch_channels: server.watch_channels().clone(),
+            ignore_servergroups: server.ignore_servergroups().clone(),
+            watch_servergroups: server.watch_servergroups().clone(),
+            show_badges: misc.show_badges(),
+            events: required_events(server),
+            max_sessions_per_uid: misc.max_sessions_per_uid(),
+            enrichment_limit: Arc::new(Semaphore::new(misc.enrichment_concurrency())),
+            packet_loss_monitor: misc.packet_loss_threshold().map(|threshold| {
+                PacketLossMonitor::new(
+                    threshold,
+                    misc.packet_loss_interval(),
+                    misc.packet_loss_cooldown(),
+                )
+            }),
+            idle_monitor: misc
+                .idle_warn()
+                .map(|threshold| IdleMonitor::new(threshold, misc.idle_check_interval())),
+            digest: misc.digest_interval().map(DigestCounter::new),
+            show_channel_path: misc.show_channel_path(),
+            empty_uid_policy: misc.empty_uid(),
+            reconnect: misc.reconnect(),
+            notify_move: misc.notify_move(),
+            show_platform: misc.show_platform(),
+            rejoin: Some(misc.rejoin_grace())
+                .filter(|grace| !grace.is_zero())
+                .map(RejoinDebounce::new),
+            announce_lifecycle: misc.announce_lifecycle(),
+            show_ip: misc.show_ip(),
+            notify_rename: misc.notify_rename(),
+            state_file: server.state_file().map(ToString::to_string),
+            alert_permission_error: misc.alert_permission_error(),
+            mode: misc.mode(),
+            visitors: server
+                .visitors_file()
+                .map(|path| KnownVisitors::load(Path::new(path)))
+                .transpose()?,
+            status: config.status().clone(),
+        })
+    }
+}
+
+/// State shared with admin, metrics and outputs, plus channels of one staff thread.
+struct StaffContext {
+    sender: EventSender,
+    command_receiver: mpsc::Receiver<StaffCommand>,
+    pause_control: Arc<PauseControl>,
+    healthy: Arc<AtomicBool>,
+    sequence: EventSequence,
+    client_map: ClientMap,
+    channel_map: ChannelMap,
+    raw_lines: Option<SharedRawLines>,
+    metrics: Arc<Metrics>,
+}
+
+async fn staff_thread(
+    mut conn: SocketConn,
+    mut command_conn: Option<SocketConn>,
+    mut recv: watch::Receiver<bool>,
+    context: StaffContext,
+    options: StaffOptions,
+) -> anyhow::Result<()> {
+    let StaffContext {
+        sender,
+        mut command_receiver,
+        pause_control,
+        healthy,
+        sequence,
+        client_map,
+        channel_map,
+        raw_lines,
+        metrics,
+    } = context;
+    let StaffOptions {
+        interval,
+        keepalive,
+        ignore_list,
+        watch_list,
+        watch_channels,
+        ignore_servergroups,
+        watch_servergroups,
+        show_badges,
+        events,
+        max_sessions_per_uid,
+        enrichment_limit,
+        mut packet_loss_monitor,
+        mut idle_monitor,
+        mut digest,
+        show_channel_path,
+        empty_uid_policy,
+        mut reconnect,
+        notify_move,
+        show_platform,
+        mut rejoin,
+        announce_lifecycle,
+        show_ip,
+        notify_rename,
+        state_file,
+        alert_permission_error,
+        mode,
+        mut visitors,
+        status,
+    } = options;
+    let server_status = |online: bool| {
+        TelegramData::ServerStatus(
+            sequence.next(),
+            Utc::now(),
+            online,
+            status.text(online).to_string(),
+        )
+    };
+    let update_online = |client_map: &HashMap<i64, OnlineClient>| {
+        metrics.clients_online().set(
+            client_map
+                .values()
+                .filter(|client| !client.is_query())
+                .count() as i64,
+        )
+    };
+    // Clients out of watch list are still tracked, only notifications are suppressed
+    let is_watched = |unique_identifier: &str| {
+        watch_list.is_empty()
+            || watch_list
+                .iter()
+                .any(|element| element.eq(unique_identifier))
+    };
+    let is_watched_channel =
+        |channel_id: i64| watch_channels.is_empty() || watch_channels.contains(&channel_id);
+    let is_watched_groups = |server_groups: &[i64]| {
+        !server_groups
+            .iter()
+            .any(|group| ignore_servergroups.contains(group))
+            && (watch_servergroups.is_empty()
+                || server_groups
+                    .iter()
+                    .any(|group| watch_servergroups.contains(group)))
+    };
+    let dump_raw_lines = || {
+        if let Some(raw_lines) = &raw_lines {
+            error!("Last received raw lines:");
+            for line in raw_lines.lock().unwrap().lines() {
+                error!("{}", line);
+            }
+        }
+    };
+    let mut session_terminated = None;
+    let mut disconnected = None;
+    let mut server_name = None;
+    // Snapshot is only saved if cache was filled from server
+    let mut loaded = false;
+    // Every exit, including errors, goes through cleanup below
+    let ret = async {
+        load_server_state(
+            query_conn(&mut conn, &mut command_conn),
+            &client_map,
+            &channel_map,
+            &ignore_list,
+        )
+        .await?;
+        loaded = true;
+        update_online(&*client_map.read().await);
+
+        let mut polling = mode == ObserveMode::Poll;
+        for (class, channel_id) in &events {
+            // Enter and left are synthesized from polled client list
+            if polling && *class == EventClass::Server {
+                continue;
+            }
+            if let Err(e) = conn.register_event(*class, *channel_id).await {
+                if *class == EventClass::Server && e.is_permission_denied() {
+                    polling = true;
+                    let message = "Query user lacks b_virtualserver_notify_register permission, \
+                        fall back to polling client list";
+                    error!("{}: {}", message, e);
+                    if alert_permission_error {
+                        sender
+                            .send(TelegramData::Alert(
+                                sequence.next(),
+                                Utc::now(),
+                                message.to_string(),
+                            ))
+                            .await;
+                    }
+                    continue;
+                }
+                if *class == EventClass::Server {
+                    return Err(anyhow!("Got error while register events: {:?}", e));
+                }
+                warn!("Register {:?} event failed, skipped: {:?}", class, e);
+            }
         }
-        let data = conn
-            .read_data()
+
+        let mut own_client_id = conn
+            .who_am_i()
             .await
-            .map_err(|e| anyhow!("Got error while read data: {:?}", e))?;
+            .map_err(|e| anyhow!("Got error while query whoami: {:?}", e))?
+            .client_id();
+        let mut skipped_while_paused = false;
+        server_name = if announce_lifecycle {
+            let server_name = query_conn(&mut conn, &mut command_conn)
+                .server_info()
+                .await
+                .map(|info| info.name().to_string())
+                .map_err(|e| warn!("Got error while query server info: {:?}", e))
+                .unwrap_or_default();
+            sender
+                .send(TelegramData::Startup(
+                    sequence.next(),
+                    Utc::now(),
+                    server_name.clone(),
+                    conn.server_id().unwrap_or_default(),
+                ))
+                .await;
+            Some(server_name)
+        } else {
+            None
+        };
+        sender.send(server_status(true)).await;
 
-        if !matches!(&data, Some(x) if !x.is_empty()) {
-            let mut signal = notify_signal.lock().await;
-            if *signal {
-                if !received {
-                    error!("Not received answer after period of time");
-                    return Err(anyhow!("Server disconnected"));
+        // Clients online at first run are not new visitors
+        if let Some(visitors) = visitors.as_mut().filter(|visitors| visitors.is_empty()) {
+            for client in client_map.read().await.values() {
+                if !client.is_query() && client.database_id() > 0 {
+                    if let Err(e) = visitors.record(client.database_id()) {
+                        warn!("{:?}", e);
+                    }
                 }
-                received = false;
-                conn.write_data("whoami\n\r")
-                    .await
-                    .map_err(|e| {
-                        error!("Got error while write data in keep alive function: {:?}", e)
-                    })
-                    .ok();
-                *signal = false;
             }
-            continue;
         }
-        let data = data.unwrap();
-        let current_time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-        for line in data.lines().map(|line| line.trim()) {
-            if line.is_empty() {
-                continue;
+
+        if let Some(path) = &state_file {
+            match Snapshot::load(Path::new(path)) {
+                Ok(Some(snapshot)) => {
+                    info!(
+                        "Loaded client snapshot saved at {}",
+                        Utc.timestamp_opt(snapshot.saved_at(), 0)
+                            .single()
+                            .map_or_else(|| "unknown time".to_string(), display_time)
+                    );
+                    let current_time = Utc::now();
+                    let (joined, left) = snapshot.diff(&*client_map.read().await);
+                    for client in left {
+                        if client.is_query
+                            || !is_watched(&client.unique_identifier)
+                            || !is_watched_channel(client.channel_id)
+                            || !is_watched_groups(&client.server_groups)
+                        {
+                            continue;
+                        }
+                        sender
+                            .send(TelegramData::from_left(
+                                sequence.next(),
+                                current_time,
+                                &NotifyClientLeftView::offline(client.client_id),
+                                client.nickname,
+                                None,
+                            ))
+                            .await;
+                    }
+                    for client_id in joined {
+                        let view = match client_map.read().await.get(&client_id) {
+                            Some(client)
+                                if !client.is_query()
+                                    && is_watched(client.unique_identifier())
+                                    && is_watched_channel(client.channel_id())
+                                    && is_watched_groups(client.server_groups()) =>
+                            {
+                                NotifyClientEnterView::catch_up(
+                                    client_id,
+                                    client.channel_id(),
+                                    client.nickname(),
+                                    client.unique_identifier(),
+                                    client.database_id(),
+                                    client.country(),
+                                    client.server_groups(),
+                                )
+                            }
+                            _ => continue,
+                        };
+                        let channel = ChannelLabel::resolve(
+                            &*channel_map.read().await,
+                            view.channel_id(),
+                            show_channel_path,
+                        );
+                        sender
+                            .send(TelegramData::from_enter(
+                                sequence.next(),
+                                current_time,
+                                view,
+                                vec!["(joined while observer was offline)".to_string()],
+                                vec![],
+                                channel,
+                            ))
+                            .await;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Ignore client snapshot: {:?}", e),
             }
-            trace!("{}", line);
-            if line.starts_with("notifycliententerview") {
-                let view = NotifyClientEnterView::from_query(line)
-                    .map_err(|e| anyhow!("Got error while deserialize data: {:?}", e))?;
-                let is_server_query = view.client_unique_identifier().eq("ServerQuery")
-                    || ignore_list
-                        .iter()
-                        .any(|element| element.eq(view.client_unique_identifier()));
-                client_map.insert(
-                    view.client_id(),
-                    (view.client_nickname().to_string(), is_server_query),
-                );
-                if is_server_query {
-                    continue;
+        }
+
+        let (keepalive_idle, keepalive_timeout) = keepalive;
+        let poll_interval = Duration::from_millis(interval);
+        let mut last_poll = Instant::now();
+        let mut last_activity = Instant::now();
+        let mut last_command_probe = Instant::now();
+        healthy.store(true, Ordering::Relaxed);
+        debug!("Loop running!");
+
+        loop {
+            if recv
+                .has_changed()
+                .map_err(|e| anyhow!("Got error in check watcher {:?}", e))?
+            {
+                shutdown_staff(
+                    &mut conn,
+                    &mut command_conn,
+                    &sender,
+                    &sequence,
+                    &mut server_name,
+                )
+                .await;
+                break;
+            }
+            let mut resumed = false;
+            if let Some(terminated) = session_terminated.take() {
+                let wait = match terminated {
+                    SessionTerminated::Kicked => 0,
+                    SessionTerminated::Banned(time) => time,
+                };
+                // Permanent ban can not be recovered by reconnect
+                if reconnect.is_none() || matches!(terminated, SessionTerminated::Banned(0)) {
+                    session_terminated.replace(terminated);
+                    break;
                 }
-                sender
-                    .send(TelegramData::from_enter(current_time.clone(), view))
+                healthy.store(false, Ordering::Relaxed);
+                warn!("{}, reconnect in {} seconds", terminated, wait);
+                if tokio::time::timeout(Duration::from_secs(wait), recv.changed())
                     .await
-                    .map_err(|_| error!("Got error while send data to telegram"))
-                    .ok();
-                continue;
+                    .is_ok()
+                {
+                    shutdown_staff(
+                        &mut conn,
+                        &mut command_conn,
+                        &sender,
+                        &sequence,
+                        &mut server_name,
+                    )
+                    .await;
+                    break;
+                }
+                match conn.reconnect().await {
+                    Ok(_) => {
+                        info!("Reconnected after session terminated");
+                        resumed = true;
+                    }
+                    Err(e) => {
+                        disconnected.replace(e);
+                    }
+                }
             }
-            if line.starts_with("notifyclientleftview") {
-                let view = NotifyClientLeftView::from_query(line)
-                    .map_err(|e| anyhow!("Got error while deserialize data: {:?}", e))?;
-                if !client_map.contains_key(&view.client_id()) {
-                    warn!("Can't find client: {:?}", view.client_id());
-                    continue;
+            if let Some(e) = disconnected.take() {
+                error!("Connection lost: {:?}", e);
+                healthy.store(false, Ordering::Relaxed);
+                sender.send(server_status(false)).await;
+                let backoff = match reconnect.as_mut() {
+                    Some(backoff) => backoff,
+                    None => {
+                        disconnected.replace(e);
+                        break;
+                    }
+                };
+                if !reconnect_with_backoff(&mut conn, &mut recv, backoff).await {
+                    shutdown_staff(
+                        &mut conn,
+                        &mut command_conn,
+                        &sender,
+                        &sequence,
+                        &mut server_name,
+                    )
+                    .await;
+                    break;
                 }
-                let nickname = client_map.get(&view.client_id()).unwrap();
-                if nickname.1 {
+                resumed = true;
+            }
+            if resumed {
+                // Rebuild cache, so clients joined or left during outage are not reported
+                // Server may answer but not be ready yet, treat failures as another disconnect
+                if let Err(e) = load_server_state(
+                    query_conn(&mut conn, &mut command_conn),
+                    &client_map,
+                    &channel_map,
+                    &ignore_list,
+                )
+                .await
+                {
+                    disconnected.replace(e);
                     continue;
                 }
+                update_online(&*client_map.read().await);
+                own_client_id = match conn.who_am_i().await {
+                    Ok(who_am_i) => who_am_i.client_id(),
+                    Err(e) => {
+                        disconnected.replace(anyhow!("Got error while query whoami: {:?}", e));
+                        continue;
+                    }
+                };
+                last_activity = Instant::now();
+                healthy.store(true, Ordering::Relaxed);
+                sender.send(server_status(true)).await;
+            }
+            if skipped_while_paused && !pause_control.is_paused() {
+                // Changes were not applied while paused, rebuild cache without reporting them
+                skipped_while_paused = false;
+                load_server_state(
+                    query_conn(&mut conn, &mut command_conn),
+                    &client_map,
+                    &channel_map,
+                    &ignore_list,
+                )
+                .await
+                .map_err(|e| warn!("Got error while resync cache after pause: {:?}", e))
+                .ok();
+                update_online(&*client_map.read().await);
+            }
+            if let Some(rejoin) = rejoin.as_mut() {
+                for left in rejoin.expired() {
+                    sender.send(left).await;
+                }
+            }
+            while let Ok(command) = command_receiver.try_recv() {
+                let query = query_conn(&mut conn, &mut command_conn);
+                match command {
+                    StaffCommand::Broadcast(message, sender) => {
+                        info!("Broadcast message from admin: {}", message);
+                        sender.send(query.global_message(&message).await).ok();
+                    }
+                    StaffCommand::Kick(client_id, sender) => {
+                        sender.send(query.kick_client(client_id).await).ok();
+                    }
+                    StaffCommand::Ban(client_id, seconds, sender) => {
+                        sender.send(query.ban_client(client_id, seconds).await).ok();
+                    }
+                    StaffCommand::ServerText(message, sender) => {
+                        debug!("Relay message to server chat: {}", message);
+                        // Stay on event connection, so own message is recognized and not relayed
+                        sender.send(conn.send_text_message(&message).await).ok();
+                    }
+                    StaffCommand::Status => match query.server_info().await {
+                        Ok(info) => {
+                            sender
+                                .send(TelegramData::Status(sequence.next(), Utc::now(), info))
+                                .await
+                        }
+                        Err(e) => warn!("Got error while query server info for status: {:?}", e),
+                    },
+                }
+            }
+            if let Some(command_conn) = command_conn
+                .as_mut()
+                .filter(|_| last_command_probe.elapsed() >= keepalive_idle)
+            {
+                last_command_probe = Instant::now();
+                keep_command_conn(command_conn, keepalive_timeout).await;
+            }
+            if let Some(monitor) = packet_loss_monitor.as_mut().filter(|monitor| monitor.due()) {
+                let clients = client_map
+                    .read()
+                    .await
+                    .iter()
+                    .filter(|(_, client)| !client.is_query())
+                    .map(|(client_id, client)| (*client_id, client.nickname().to_string()))
+                    .collect::<Vec<_>>();
+                monitor.retain(|client_id| clients.iter().any(|(id, _)| *id == client_id));
+                for (client_id, nickname) in clients {
+                    let info = {
+                        let _permit = enrichment_limit.acquire().await?;
+                        query_conn(&mut conn, &mut command_conn)
+                            .client_info(client_id)
+                            .await
+                    };
+                    let packet_loss = match info {
+                        // Packet loss is not meaningful for just connected clients
+                        Ok(info) if info.connection_packets_sent_total() < MIN_PACKETS_SENT => {
+                            continue
+                        }
+                        Ok(info) => info.packet_loss(),
+                        Err(e) => {
+                            warn!("Got error while query client info: {:?}", e);
+                            continue;
+                        }
+                    };
+                    if monitor.should_alert(client_id, packet_loss) {
+                        sender
+                            .send(TelegramData::PacketLoss(
+                                sequence.next(),
+                                Utc::now(),
+                                client_id,
+                                nickname,
+                                packet_loss,
+                            ))
+                            .await;
+                    }
+                }
+            }
+            if let Some(monitor) = idle_monitor.as_mut().filter(|monitor| monitor.due()) {
+                match query_conn(&mut conn, &mut command_conn)
+                    .query_clients()
+                    .await
+                {
+                    Ok(clients) => {
+                        let clients = clients
+                            .into_iter()
+                            .filter(|client| client.client_type() == 0)
+                            .collect::<Vec<_>>();
+                        monitor.retain(|client_id| {
+                            clients.iter().any(|client| client.client_id() == client_id)
+                        });
+                        let client_map = client_map.read().await;
+                        for client in clients {
+                            let ignored = client_map
+                                .get(&client.client_id())
+                                .map_or(false, |online| online.is_query());
+                            if ignored
+                                || !is_watched(client.client_unique_identifier())
+                                || !is_watched_groups(&client.server_groups())
+                            {
+                                continue;
+                            }
+                            if monitor.should_warn(client.client_id(), client.idle_time()) {
+                                sender
+                                    .send(TelegramData::Idle(
+                                        sequence.next(),
+                                        Utc::now(),
+                                        client.client_id(),
+                                        client.client_nickname().to_string(),
+                                        client.idle_time(),
+                                    ))
+                                    .await;
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Got error while query client idle time: {:?}", e),
+                }
+            }
+            if let Some(digest) = digest.as_mut().filter(|digest| digest.due()) {
+                let server_info = query_conn(&mut conn, &mut command_conn)
+                    .server_info()
+                    .await
+                    .map_err(|e| warn!("Got error while query server info, omitted: {:?}", e))
+                    .ok();
+                let online = client_map
+                    .read()
+                    .await
+                    .values()
+                    .filter(|client| !client.is_query())
+                    .count();
                 sender
-                    .send(TelegramData::from_left(
-                        current_time.clone(),
-                        &view,
-                        nickname.0.clone(),
+                    .send(TelegramData::Digest(
+                        sequence.next(),
+                        Utc::now(),
+                        digest.take(online, server_info),
                     ))
+                    .await;
+            }
+            let polled = if polling && last_poll.elapsed() >= poll_interval {
+                last_poll = Instant::now();
+                match query_conn(&mut conn, &mut command_conn)
+                    .query_clients()
                     .await
-                    .map_err(|_| error!("Got error while send data to telegram"))
-                    .ok();
-                client_map.remove(&view.client_id());
+                {
+                    Ok(clients) => poll::diff_clients(&clients, &*client_map.read().await),
+                    Err(e) => {
+                        warn!("Got error while poll client list: {:?}", e);
+                        Vec::new()
+                    }
+                }
+            } else {
+                Vec::new()
+            };
+            let data = match conn.read_data().await {
+                Ok(data) => data,
+                Err(e) => {
+                    disconnected.replace(anyhow!("Got error while read data: {:?}", e));
+                    continue;
+                }
+            };
+            // Synthesized lines are processed as if server sent them
+            let data = if polled.is_empty() {
+                data
+            } else {
+                let mut data = data.unwrap_or_default();
+                for line in polled {
+                    data.push_str(&line);
+                    data.push_str("\n\r");
+                }
+                Some(data)
+            };
+
+            if !matches!(&data, Some(x) if !x.is_empty()) {
+                // Only probe connection which has been idle, busy connection is obviously alive
+                if last_activity.elapsed() >= keepalive_idle {
+                    match tokio::time::timeout(keepalive_timeout, conn.who_am_i()).await {
+                        Ok(Ok(_)) => {
+                            last_activity = Instant::now();
+                            healthy.store(true, Ordering::Relaxed);
+                        }
+                        Ok(Err(e)) => {
+                            disconnected.replace(anyhow!("Got error in keepalive: {:?}", e));
+                        }
+                        Err(_) => {
+                            warn!(
+                                "Keepalive whoami is not answered within {:?}",
+                                keepalive_timeout
+                            );
+                            disconnected.replace(anyhow!("Server disconnected"));
+                        }
+                    }
+                }
                 continue;
             }
-            if line.contains("virtualserver_status=") {
-                received = true;
+            let data = data.unwrap();
+            last_activity = Instant::now();
+            let current_time = Utc::now();
+            let paused = pause_control.is_paused();
+            for line in data.lines().map(|line| line.trim()) {
+                if line.is_empty() {
+                    continue;
+                }
+                if let Some(raw_lines) = &raw_lines {
+                    raw_lines.lock().unwrap().push(line);
+                }
+                if line.contains("virtualserver_status=") {
+                    healthy.store(true, Ordering::Relaxed);
+                    continue;
+                }
+                // Keepalive answer is still checked, other events are dropped while paused
+                if paused {
+                    skipped_while_paused = true;
+                    continue;
+                }
+                trace!("{}", line);
+                let event = Event::parse(line).map_err(|e| {
+                    dump_raw_lines();
+                    anyhow!("Got error while deserialize data: {:?}", e)
+                })?;
+                match event {
+                    Event::ClientEnterView(view) => {
+                        let has_uid = view.has_unique_identifier();
+                        if !has_uid {
+                            warn!(
+                                "Client {}({}) has empty unique identifier",
+                                view.client_nickname(),
+                                view.client_id()
+                            );
+                        }
+                        let is_server_query = view.is_query()
+                            || if has_uid {
+                                ignore_list
+                                    .iter()
+                                    .any(|element| element.eq(view.client_unique_identifier()))
+                            } else {
+                                empty_uid_policy == EmptyUidPolicy::Ignore
+                            };
+                        client_map.write().await.insert(
+                            view.client_id(),
+                            OnlineClient::new(
+                                view.client_nickname().to_string(),
+                                view.client_unique_identifier().to_string(),
+                                view.channel_id(),
+                                is_server_query,
+                                Some(Local::now()),
+                            )
+                            .with_database_id(view.client_database_id())
+                            .with_server_groups(view.server_groups())
+                            .with_country(view.client_country()),
+                        );
+                        if is_server_query {
+                            continue;
+                        }
+                        let first_seen = match visitors.as_mut() {
+                            Some(visitors) if view.client_database_id() > 0 => visitors
+                                .record(view.client_database_id())
+                                .map_err(|e| warn!("{:?}", e))
+                                .unwrap_or(false),
+                            _ => false,
+                        };
+                        metrics.clients_joined().inc();
+                        if let Some(digest) = digest.as_mut() {
+                            digest.record_join();
+                        }
+                        update_online(&*client_map.read().await);
+                        if !is_watched(view.client_unique_identifier())
+                            || !is_watched_channel(view.channel_id())
+                            || !is_watched_groups(&view.server_groups())
+                        {
+                            continue;
+                        }
+                        if let Some(rejoin) =
+                            rejoin.as_mut().filter(|_| view.client_database_id() > 0)
+                        {
+                            match rejoin.rejoin(view.client_database_id()) {
+                                Rejoin::Suppressed => {
+                                    debug!(
+                                        "Client {}({}) rejoined within grace period, \
+                                        notification suppressed",
+                                        view.client_nickname(),
+                                        view.client_database_id()
+                                    );
+                                    continue;
+                                }
+                                Rejoin::Expired(left) => sender.send(left).await,
+                                Rejoin::NotHeld => {}
+                            }
+                        }
+                        if first_seen {
+                            sender
+                                .send(TelegramData::FirstSeen(
+                                    sequence.next(),
+                                    current_time,
+                                    view.client_id(),
+                                    view.client_nickname().to_string(),
+                                ))
+                                .await;
+                        }
+                        let info = if show_badges || show_ip {
+                            let _permit = enrichment_limit.acquire().await?;
+                            query_conn(&mut conn, &mut command_conn)
+                                .client_info(view.client_id())
+                                .await
+                                .map_err(|e| warn!("Got error while query client info: {:?}", e))
+                                .ok()
+                        } else {
+                            None
+                        };
+                        let badges = match &info {
+                            Some(info) if show_badges => info.badge_tags(),
+                            _ => vec![],
+                        };
+                        let mut tags = badges.clone();
+                        if let Some(ip) = info.as_ref().and_then(|info| info.client_ip()) {
+                            if show_ip {
+                                tags.push(format!("🌐 {}", ip));
+                            }
+                        }
+                        if show_platform && !view.client_platform().is_empty() {
+                            tags.insert(0, format!("({})", view.client_platform()));
+                        }
+                        let session_count = if max_sessions_per_uid.is_some() && has_uid {
+                            Some(
+                                sessions_of(
+                                    &*client_map.read().await,
+                                    view.client_unique_identifier(),
+                                )
+                                .len(),
+                            )
+                        } else {
+                            None
+                        };
+                        let unique_identifier = view.client_unique_identifier().to_string();
+                        let nickname = view.client_nickname().to_string();
+                        let channel = ChannelLabel::resolve(
+                            &*channel_map.read().await,
+                            view.channel_id(),
+                            show_channel_path,
+                        );
+                        sender
+                            .send(TelegramData::from_enter(
+                                sequence.next(),
+                                current_time,
+                                view,
+                                tags,
+                                badges,
+                                channel,
+                            ))
+                            .await;
+                        if let (Some(limit), Some(count)) = (max_sessions_per_uid, session_count) {
+                            if count > limit {
+                                sender
+                                    .send(TelegramData::SessionAlert(
+                                        sequence.next(),
+                                        current_time,
+                                        unique_identifier,
+                                        nickname,
+                                        count,
+                                    ))
+                                    .await;
+                            }
+                        }
+                    }
+                    Event::Other(line)
+                        if line.starts_with("notifychannelcreated")
+                            || line.starts_with("notifychanneledited")
+                            || line.starts_with("notifychanneldeleted")
+                            || line.starts_with("notifychannelmoved") =>
+                    {
+                        load_channels(query_conn(&mut conn, &mut command_conn), &channel_map)
+                            .await
+                            .map_err(|e| warn!("Got error while refresh channel cache: {:?}", e))
+                            .ok();
+                    }
+                    Event::TextMessage(view) => {
+                        // Never mirror messages sent by observer itself
+                        if view.invoker_id() == own_client_id {
+                            continue;
+                        }
+                        let nickname = view.invoker_name().to_string();
+                        sender
+                            .send(TelegramData::Text(
+                                sequence.next(),
+                                current_time,
+                                view,
+                                nickname,
+                            ))
+                            .await;
+                    }
+                    Event::ClientUpdated(view) => {
+                        let nickname = match view.client_nickname() {
+                            Some(nickname) => nickname.to_string(),
+                            None => continue,
+                        };
+                        // Keep cache current, so left message shows latest nickname
+                        let (client, old) =
+                            match client_map.write().await.get_mut(&view.client_id()) {
+                                Some(client) if client.nickname() != nickname => {
+                                    let old = client.nickname().to_string();
+                                    client.set_nickname(nickname.clone());
+                                    (client.clone(), old)
+                                }
+                                _ => continue,
+                            };
+                        if client.is_query()
+                            || !notify_rename
+                            || !is_watched(client.unique_identifier())
+                            || !is_watched_channel(client.channel_id())
+                            || !is_watched_groups(client.server_groups())
+                        {
+                            continue;
+                        }
+                        sender
+                            .send(TelegramData::Renamed(
+                                sequence.next(),
+                                current_time,
+                                view.client_id(),
+                                old,
+                                nickname,
+                            ))
+                            .await;
+                    }
+                    Event::ClientMoved(view) => {
+                        let (client, previous_channel) =
+                            match client_map.write().await.get_mut(&view.client_id()) {
+                                Some(client) => {
+                                    let previous_channel = client.channel_id();
+                                    client.set_channel_id(view.target_channel_id());
+                                    (client.clone(), previous_channel)
+                                }
+                                None => {
+                                    warn!("Can't find client: {:?}", view.client_id());
+                                    continue;
+                                }
+                            };
+                        if client.is_query()
+                            || !is_watched(client.unique_identifier())
+                            || !is_watched_groups(client.server_groups())
+                        {
+                            continue;
+                        }
+                        let channel = ChannelLabel::resolve(
+                            &*channel_map.read().await,
+                            view.target_channel_id(),
+                            show_channel_path,
+                        );
+                        // Moving across watched channels boundary is reported as enter or left
+                        match (
+                            is_watched_channel(previous_channel),
+                            is_watched_channel(view.target_channel_id()),
+                        ) {
+                            (true, false) => {
+                                sender
+                                    .send(TelegramData::from_left(
+                                        sequence.next(),
+                                        current_time,
+                                        &NotifyClientLeftView::moved_out(
+                                            view.client_id(),
+                                            channel.name(),
+                                        ),
+                                        client.nickname().to_string(),
+                                        None,
+                                    ))
+                                    .await;
+                                continue;
+                            }
+                            (false, true) => {
+                                sender
+                                    .send(TelegramData::from_enter(
+                                        sequence.next(),
+                                        current_time,
+                                        NotifyClientEnterView::catch_up(
+                                            view.client_id(),
+                                            view.target_channel_id(),
+                                            client.nickname(),
+                                            client.unique_identifier(),
+                                            client.database_id(),
+                                            client.country(),
+                                            client.server_groups(),
+                                        ),
+                                        vec!["(moved in)".to_string()],
+                                        vec![],
+                                        channel,
+                                    ))
+                                    .await;
+                                continue;
+                            }
+                            (false, false) => continue,
+                            (true, true) if !notify_move => continue,
+                            (true, true) => {}
+                        }
+                        sender
+                            .send(TelegramData::Moved(
+                                sequence.next(),
+                                current_time,
+                                view,
+                                client.nickname().to_string(),
+                                channel,
+                            ))
+                            .await;
+                    }
+                    Event::ClientLeftView(view) => {
+                        if view.client_id() == own_client_id {
+                            error!(
+                                "Own query session was terminated by {}: {:?}",
+                                view.invoker_name(),
+                                view
+                            );
+                            session_terminated.replace(SessionTerminated::from(&view));
+                            sender
+                                .send(TelegramData::SessionTerminated(
+                                    sequence.next(),
+                                    current_time,
+                                    view,
+                                ))
+                                .await;
+                            break;
+                        }
+                        let client = match client_map.write().await.remove(&view.client_id()) {
+                            Some(client) => client,
+                            None => {
+                                warn!("Can't find client: {:?}", view.client_id());
+                                continue;
+                            }
+                        };
+                        if client.is_query() {
+                            continue;
+                        }
+                        metrics.clients_left().inc();
+                        if let Some(digest) = digest.as_mut() {
+                            digest.record_left();
+                        }
+                        update_online(&*client_map.read().await);
+                        if !is_watched(client.unique_identifier())
+                            || !is_watched_channel(client.channel_id())
+                            || !is_watched_groups(client.server_groups())
+                        {
+                            continue;
+                        }
+                        let left = TelegramData::from_left(
+                            sequence.next(),
+                            current_time,
+                            &view,
+                            client.nickname().to_string(),
+                            client.connected_at().and_then(|connected_at| {
+                                (Local::now() - connected_at).to_std().ok()
+                            }),
+                        );
+                        match rejoin.as_mut() {
+                            Some(rejoin) if client.database_id() > 0 => {
+                                rejoin.hold(client.database_id(), left)
+                            }
+                            _ => sender.send(left).await,
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if session_terminated.is_some() {
+                // Handled at top of loop, reconnect or stop without waiting for next poll
                 continue;
             }
+            if let Ok(_) =
+                tokio::time::timeout(Duration::from_millis(interval), recv.changed()).await
+            {
+                shutdown_staff(
+                    &mut conn,
+                    &mut command_conn,
+                    &sender,
+                    &sequence,
+                    &mut server_name,
+                )
+                .await;
+                break;
+            }
         }
-        if let Ok(_) = tokio::time::timeout(Duration::from_millis(interval), recv.changed()).await {
-            info!("Exit from staff thread!");
-            conn.logout().await.ok();
-            break;
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    if ret.is_err() {
+        shutdown_staff(
+            &mut conn,
+            &mut command_conn,
+            &sender,
+            &sequence,
+            &mut server_name,
+        )
+        .await;
+    }
+    healthy.store(false, Ordering::Relaxed);
+    if let Some(rejoin) = rejoin.as_mut() {
+        for left in rejoin.drain() {
+            sender.send(left).await;
         }
     }
-    sender
-        .send(TelegramData::Terminate)
-        .await
-        .map_err(|_| error!("Got error while send terminate signal"))
-        .ok();
+    if let Some(path) = state_file.as_ref().filter(|_| loaded) {
+        Snapshot::new(&*client_map.read().await)
+            .save(Path::new(path))
+            .map_err(|e| error!("{:?}", e))
+            .ok();
+    }
+    sender.send(TelegramData::Terminate).await;
+    ret?;
+    if let Some(e) = session_terminated {
+        return Err(e.into());
+    }
+    if let Some(e) = disconnected {
+        return Err(e);
+    }
     Ok(())
 }
 
-async fn observer(conn: SocketConn, config: Config) -> anyhow::Result<()> {
+async fn heartbeat_thread(
+    url: String,
+    interval: u64,
+    healthy: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    loop {
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        if !healthy.load(Ordering::Relaxed) {
+            debug!("Connection is not healthy, skip heartbeat");
+            continue;
+        }
+        match client.get(&url).send().await {
+            Ok(response) if !response.status().is_success() => {
+                warn!("Heartbeat got unexpected status: {}", response.status())
+            }
+            Ok(_) => trace!("Heartbeat sent"),
+            Err(e) => warn!("Got error while send heartbeat: {:?}", e),
+        }
+    }
+}
+
+/// Ask staff thread to send status every `interval`, stops once staff thread exited.
+async fn status_thread(interval: Duration, staff_sender: mpsc::Sender<StaffCommand>) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if staff_sender.send(StaffCommand::Status).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Event classes should be registered, computed from enabled features.
+///
+/// `event=server` is always required, others are optional and their failure will not abort.
+fn required_events(server: &Server) -> Vec<(EventClass, Option<i64>)> {
+    let mut events = vec![(EventClass::Server, None)];
+    events.extend(
+        server
+            .subscribe_channels()
+            .iter()
+            .map(|channel_id| (EventClass::Channel, Some(*channel_id))),
+    );
+    // Channel id 0 means all channels, also required to keep channel cache up to date
+    if server.subscribe_channels().is_empty() {
+        events.push((EventClass::Channel, Some(0)));
+    }
+    for class in server.events() {
+        let event = match class {
+            EventClass::Channel => (EventClass::Channel, Some(0)),
+            class => (*class, None),
+        };
+        // Channel events may already be covered by subscribe_channels
+        if *class == EventClass::Channel
+            && events
+                .iter()
+                .any(|(class, _)| *class == EventClass::Channel)
+        {
+            continue;
+        }
+        if !events.contains(&event) {
+            events.push(event);
+        }
+    }
+    events
+}
+
+/// Every virtual server has its own connection, staff thread and outputs,
+/// `conns` should be in the same order as `config.servers()`.
+async fn observer(
+    conns: Vec<(SocketConn, Option<SocketConn>)>,
+    config: Config,
+) -> anyhow::Result<()> {
+    let quiet_hours = config.misc().quiet_hours()?;
     let (exit_sender, exit_receiver) = watch::channel(false);
-    let (telegram_sender, telegram_receiver) = mpsc::channel(4096);
-
-    let keepalive_signal = Arc::new(Mutex::new(false));
-    let alt_signal = keepalive_signal.clone();
-
-    let staff_handler = tokio::spawn(staff_thread(
-        conn,
-        exit_receiver,
-        telegram_sender,
-        config.misc().interval(),
-        alt_signal,
-        config.server().ignore_user_name(),
-    ));
-    let telegram_handler = tokio::spawn(telegram_thread(
-        config.telegram().api_key().to_string(),
-        config.telegram().target(),
-        config.telegram().api_server(),
-        telegram_receiver,
-    ));
+    let sink_policy = config
+        .database()
+        .as_ref()
+        .map(|database| database.sink_policy())
+        .unwrap_or_default();
+    let (database_sender, database_handler) = match config.database() {
+        Some(database) => {
+            let (sender, receiver) = mpsc::channel(4096);
+            (
+                Some(sender),
+                Some(tokio::spawn(database::database_thread(
+                    database.path().to_string(),
+                    database.retention_days(),
+                    receiver,
+                ))),
+            )
+        }
+        None => (None, None),
+    };
+
+    let pause_control = Arc::new(PauseControl::default());
+    let healthy = Arc::new(AtomicBool::new(false));
+    let metrics = Arc::new(Metrics::new()?);
+    let templates = SharedTemplates::default();
+    let is_leader = Arc::new(AtomicBool::new(config.misc().leader_lock_file().is_none()));
+    let leader_handler = config.misc().leader_lock_file().map(|path| {
+        tokio::spawn(leader::leader_thread(
+            path.to_string(),
+            config.misc().leader_check_interval(),
+            is_leader.clone(),
+        ))
+    });
+    // Watcher stops when dropped, keep it until observer exits
+    let _template_watcher = match config.templates() {
+        Some(config) => match config.file() {
+            Some(file) => {
+                *templates.write().unwrap() = Templates::load(Path::new(file))?;
+                Some(template::watch_templates(file.into(), templates.clone())?)
+            }
+            None => {
+                *templates.write().unwrap() = Templates::inline(config.enter(), config.left())
+                    .map_err(|e| anyhow!("Got error while compile templates: {:?}", e))?;
+                None
+            }
+        },
+        None => None,
+    };
+    let raw_lines = match config.misc().raw_line_buffer() {
+        0 => None,
+        capacity => Some(Arc::new(std::sync::Mutex::new(RawLineBuffer::new(
+            capacity,
+        )))),
+    };
+
+    let mut output_handlers = Vec::new();
+    let mut admin_state = None;
+    let mut status_handlers = Vec::new();
+    let (staff_done_sender, mut staff_done) = mpsc::channel(conns.len().max(1));
+    for (index, ((conn, command_conn), server)) in
+        conns.into_iter().zip(config.servers()).enumerate()
+    {
+        let mut outputs = Vec::new();
+        let output = OutputOptions::new(&config, server, templates.clone(), is_leader.clone());
+        if let Some(telegram) = config.telegram() {
+            let (sender, receiver) = mpsc::channel(4096);
+            outputs.push(sender);
+            output_handlers.push(tokio::spawn(telegram_thread(
+                TelegramOptions::new(telegram, server, quiet_hours),
+                output.clone(),
+                metrics.clone(),
+                receiver,
+            )));
+        }
+        if let Some(discord) = config.discord() {
+            let (sender, receiver) = mpsc::channel(4096);
+            outputs.push(sender);
+            output_handlers.push(tokio::spawn(discord::discord_thread(
+                discord.webhook_url().to_string(),
+                output.clone(),
+                receiver,
+            )));
+        }
+        if let Some(matrix) = config.matrix() {
+            let (sender, receiver) = mpsc::channel(4096);
+            outputs.push(sender);
+            output_handlers.push(tokio::spawn(matrix::matrix_thread(
+                matrix.homeserver().to_string(),
+                matrix.access_token().to_string(),
+                matrix.room_id().to_string(),
+                output.clone(),
+                receiver,
+            )));
+        }
+        if let Some(webhook) = config.webhook() {
+            let (sender, receiver) = mpsc::channel(4096);
+            outputs.push(sender);
+            output_handlers.push(tokio::spawn(webhook::webhook_thread(
+                webhook.url().to_string(),
+                webhook.headers().clone(),
+                webhook.timeout(),
+                webhook.max_retries(),
+                is_leader.clone(),
+                config.misc().dry_run(),
+                receiver,
+            )));
+        }
+
+        let client_map = ClientMap::default();
+        let channel_map = ChannelMap::default();
+        let (staff_command_sender, staff_command_receiver) = mpsc::channel(16);
+        if let Some(interval) = config.misc().status_interval() {
+            status_handlers.push(tokio::spawn(status_thread(
+                interval,
+                staff_command_sender.clone(),
+            )));
+        }
+        // Admin commands and raw line buffer only work with primary server
+        let raw_lines = if index == 0 {
+            admin_state.replace((
+                client_map.clone(),
+                channel_map.clone(),
+                staff_command_sender,
+                raw_lines.clone(),
+            ));
+            raw_lines.clone()
+        } else {
+            None
+        };
+        let staff = staff_thread(
+            conn,
+            command_conn,
+            exit_receiver.clone(),
+            StaffContext {
+                sender: EventSender {
+                    outputs,
+                    database: database_sender.clone(),
+                    policy: sink_policy,
+                },
+                command_receiver: staff_command_receiver,
+                pause_control: pause_control.clone(),
+                healthy: healthy.clone(),
+                sequence: EventSequence::default(),
+                client_map,
+                channel_map,
+                raw_lines,
+                metrics: metrics.clone(),
+            },
+            StaffOptions::new(&config, server)?,
+        );
+        let staff_done_sender = staff_done_sender.clone();
+        tokio::spawn(async move {
+            staff_done_sender.send(staff.await).await.ok();
+        });
+    }
+    // Database stops after every staff thread dropped its sender
+    drop(database_sender);
+    drop(staff_done_sender);
+    let (client_map, channel_map, staff_command_sender, raw_lines) =
+        admin_state.ok_or_else(|| anyhow!("No server configured"))?;
+    let http_handler = config.http().as_ref().map(|http_config| {
+        tokio::spawn(http::server_thread(
+            client_map.clone(),
+            channel_map.clone(),
+            http_config.bind().to_string(),
+        ))
+    });
+    let admin_handler = config.telegram().as_ref().map(|telegram| {
+        tokio::spawn(admin::admin_thread(
+            telegram.api_key().to_string(),
+            telegram.api_server(),
+            AdminContext::new(
+                &config,
+                telegram,
+                pause_control,
+                client_map,
+                channel_map,
+                raw_lines,
+                staff_command_sender,
+            ),
+        ))
+    });
+    let pushgateway_handler = config.metrics().as_ref().and_then(|metrics_config| {
+        metrics_config.pushgateway_url().map(|url| {
+            tokio::spawn(metrics::pushgateway_thread(
+                metrics.clone(),
+                url.to_string(),
+                metrics_config.job(),
+                metrics_config.push_interval(),
+            ))
+        })
+    });
+    let metrics_server_handler = config
+        .metrics()
+        .as_ref()
+        .and_then(|metrics_config| metrics_config.bind())
+        .map(|bind| tokio::spawn(metrics::server_thread(metrics.clone(), bind.to_string())));
+    let heartbeat_handler = config.misc().heartbeat_url().map(|url| {
+        tokio::spawn(heartbeat_thread(
+            url.to_string(),
+            config.misc().heartbeat_interval(),
+            healthy,
+        ))
+    });
 
+    let mut staff_result = Ok(());
     tokio::select! {
         _ = async {
             tokio::signal::ctrl_c().await.unwrap();
@@ -320,64 +2463,375 @@ async fn observer(conn: SocketConn, config: Config) -> anyhow::Result<()> {
             std::process::exit(137);
         } => {
         }
-        _ = async move {
-            loop {
-                tokio::time::sleep(Duration::from_secs(30)).await;
-                let mut i = keepalive_signal.lock().await;
-                *i = true;
-            }
-        } => {}
-        ret = staff_handler => {
-            ret??
+        ret = staff_done.recv() => {
+            // Wait telegram thread send remaining messages before return error
+            staff_result = ret.unwrap_or(Ok(()));
         }
     }
+    // One staff thread exited, stop others as well
+    exit_sender.send(true).ok();
+    if let Some(handler) = admin_handler {
+        handler.abort();
+    }
+    if let Some(handler) = heartbeat_handler {
+        handler.abort();
+    }
+    for handler in status_handlers {
+        handler.abort();
+    }
+    if let Some(handler) = pushgateway_handler {
+        handler.abort();
+    }
+    if let Some(handler) = metrics_server_handler {
+        handler.abort();
+    }
+    if let Some(handler) = http_handler {
+        handler.abort();
+    }
+    if let Some(handler) = leader_handler {
+        handler.abort();
+    }
+    // Ctrl-C within grace period is ignored, so queued messages still have chance to be delivered
+    let deadline = tokio::time::Instant::now() + config.misc().shutdown_timeout();
     tokio::select! {
         _ = async {
-            tokio::signal::ctrl_c().await.unwrap();
-            error!("Force exit program.");
-            std::process::exit(137);
+            loop {
+                tokio::signal::ctrl_c().await.unwrap();
+                let now = tokio::time::Instant::now();
+                if now >= deadline {
+                    error!("Force exit program.");
+                    std::process::exit(137);
+                }
+                warn!(
+                    "Flushing pending messages, force exit is available after {}s",
+                    (deadline - now).as_secs() + 1
+                );
+            }
         } => {
 
         }
-        ret = telegram_handler => {
-            ret??;
+        _ = tokio::time::sleep_until(deadline) => {
+            error!("Shutdown grace period exceeded, pending messages are dropped");
         }
+        ret = async {
+            // Outputs are terminated by their staff thread
+            while let Some(ret) = staff_done.recv().await {
+                if let Err(e) = ret {
+                    error!("Got error in staff thread: {:?}", e);
+                }
+            }
+            // Wait every output send remaining messages
+            for handler in output_handlers {
+                handler.await??;
+            }
+            Ok::<_, anyhow::Error>(())
+        } => {
+            ret?;
+        }
+    }
+    if let Some(handler) = database_handler {
+        handler.await??;
     }
+    staff_result
+}
+
+/// Connect, login and run `whoami` once, used as container liveness probe.
+async fn healthcheck(config: Config) -> anyhow::Result<()> {
+    let mut conn = init_connection(config.raw_query(), config.server().server_id()).await?;
+    let who_am_i = conn
+        .who_am_i()
+        .await
+        .map_err(|e| anyhow!("Got error while query whoami: {:?}", e))?;
+    info!("Healthcheck passed, client id: {}", who_am_i.client_id());
+    conn.logout().await.ok();
     Ok(())
 }
 
-async fn configure_file_bootstrap<P: AsRef<Path>>(path: P) -> anyhow::Result<()> {
-    let config = Config::try_from(path.as_ref())?;
-    observer(
-        init_connection(
-            config.raw_query().server(),
-            config.raw_query().port(),
-            config.raw_query().user(),
-            config.raw_query().password(),
-            config.server().server_id(),
-        )
-        .await?,
-        config,
-    )
-    .await
+async fn configure_file_bootstrap(config: Config) -> anyhow::Result<()> {
+    if let Some(telegram) = config.telegram() {
+        if telegram.api_key().is_empty()
+            && !telegram.allow_empty_token()
+            && !config.misc().dry_run()
+        {
+            return Err(anyhow!(
+                "Telegram api_key is empty, set telegram.allow_empty_token = true to run without sending message"
+            ));
+        }
+    } else if config.discord().is_none() && config.matrix().is_none() && config.webhook().is_none()
+    {
+        return Err(anyhow!(
+            "No output configured, at least one of [telegram], [discord], [matrix] or [webhook] is required"
+        ));
+    }
+    let mut conns = Vec::new();
+    for server in config.servers() {
+        let mut attempt = 0;
+        let conn = loop {
+            match init_connection(config.raw_query(), server.server_id()).await {
+                Ok(conn) => break conn,
+                Err(e) => match config.raw_query().startup_retries() {
+                    Some(retries) if attempt < retries => {
+                        attempt += 1;
+                        let delay = config.raw_query().startup_retry_delay();
+                        warn!(
+                            "Startup of server {} failed (attempt {}/{}), retry in {:?}: {:?}",
+                            server.server_id(),
+                            attempt,
+                            retries,
+                            delay,
+                            e
+                        );
+                        tokio::time::sleep(delay).await;
+                    }
+                    Some(_) => return Err(e),
+                    None => config.raw_query().retry().check(e).await?,
+                },
+            }
+        };
+        let command_conn = if config.raw_query().command_connection() {
+            Some(
+                init_connection(config.raw_query(), server.server_id())
+                    .await
+                    .map_err(|e| anyhow!("Got error while open command connection: {:?}", e))?,
+            )
+        } else {
+            None
+        };
+        conns.push((conn, command_conn));
+    }
+    observer(conns, config).await
 }
 
 fn main() -> anyhow::Result<()> {
     let matches = Command::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
-        .args(&[arg!([CONFIG_FILE] "Override default configure file location")])
+        .args(&[
+            arg!([CONFIG_FILE] "Override default configure file location"),
+            arg!(--"generate-config" "Write commented configure template to CONFIG_FILE then exit"),
+            arg!(--force "Overwrite existing file when generating configure template"),
+        ])
+        .subcommand(
+            Command::new("healthcheck")
+                .about("Check query connection and credentials, then exit")
+                .args(&[arg!([CONFIG_FILE] "Override default configure file location")]),
+        )
         .get_matches();
 
-    env_logger::Builder::from_default_env()
+    let subcommand = matches.subcommand();
+    let config_file = subcommand
+        .and_then(|(_, matches)| matches.value_of("CONFIG_FILE"))
+        .or_else(|| matches.value_of("CONFIG_FILE"))
+        .unwrap_or("config.toml");
+    if matches.is_present("generate-config") {
+        config::write_template(Path::new(config_file), matches.is_present("force"))?;
+        println!("Configure template is written to {}", config_file);
+        return Ok(());
+    }
+    let config = Config::try_from(Path::new(config_file))?;
+    TIME_DISPLAY.set(config.misc().time_display()?).ok();
+
+    let mut builder = env_logger::Builder::from_default_env();
+    builder
         .filter_module("rustls", LevelFilter::Warn)
-        .filter_module("reqwest", LevelFilter::Warn)
-        .init();
-    tokio::runtime::Builder::new_multi_thread()
+        .filter_module("reqwest", LevelFilter::Warn);
+    if config.misc().log_format() == LogFormat::Json {
+        builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "{}",
+                serde_json::json!({
+                    "timestamp": Utc::now().to_rfc3339(),
+                    "level": record.level().as_str(),
+                    "module": record.module_path().unwrap_or_default(),
+                    "message": record.args().to_string(),
+                })
+            )
+        });
+    }
+    builder.init();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
-        .unwrap()
-        .block_on(configure_file_bootstrap(
-            matches.value_of("CONFIG_FILE").unwrap_or("config.toml"),
-        ))?;
+        .unwrap();
+    match subcommand {
+        Some(("healthcheck", _)) => runtime.block_on(healthcheck(config))?,
+        _ => runtime.block_on(configure_file_bootstrap(config))?,
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        staff_thread, EventSender, EventSequence, StaffContext, StaffOptions, TelegramData,
+    };
+    use crate::admin::PauseControl;
+    use crate::database::SinkPolicy;
+    use crate::datastructures::config::Config;
+    use crate::datastructures::{FromQueryString, NotifyClientEnterView, NotifyClientLeftView};
+    use crate::format::MessageFormat;
+    use crate::metrics::Metrics;
+    use crate::roster::ChannelLabel;
+    use crate::socketlib::SocketConn;
+    use crate::template::Templates;
+    use chrono::Utc;
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+    use tokio::sync::{mpsc, watch};
+
+    const OK: &str = "error id=0 msg=ok\n\r";
+
+    /// Answer commands with canned replies, push `notifies` in a separate write after first `whoami`.
+    async fn mock_server(stream: DuplexStream, notifies: Vec<&'static str>) {
+        let (reader, mut writer) = tokio::io::split(stream);
+        writer
+            .write_all(b"TS3\n\rWelcome to the TeamSpeak 3 ServerQuery interface\n\r")
+            .await
+            .unwrap();
+        let mut reader = BufReader::new(reader);
+        let mut command = Vec::new();
+        let mut notifies = Some(notifies);
+        loop {
+            command.clear();
+            if reader.read_until(b'\r', &mut command).await.unwrap() == 0 {
+                break;
+            }
+            let command = String::from_utf8_lossy(&command);
+            let reply = if command.starts_with("channellist") {
+                format!("cid=1 pid=0 channel_name=Lobby\n\r{}", OK)
+            } else if command.starts_with("clientlist") {
+                format!(
+                    "clid=1 cid=1 client_database_id=1 client_nickname=observer client_type=1 client_unique_identifier=serveradmin\n\r{}",
+                    OK
+                )
+            } else if command.starts_with("whoami") {
+                format!("client_id=1 virtualserver_id=1\n\r{}", OK)
+            } else if command.starts_with("quit") {
+                break;
+            } else {
+                OK.to_string()
+            };
+            writer.write_all(reply.as_bytes()).await.unwrap();
+            if command.starts_with("whoami") {
+                if let Some(notifies) = notifies.take() {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    let mut data = String::new();
+                    for notify in notifies {
+                        data.push_str(notify);
+                        data.push_str("\n\r");
+                    }
+                    writer.write_all(data.as_bytes()).await.unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_format_escape_html() {
+        let enter = TelegramData::from_enter(
+            1,
+            Utc::now(),
+            NotifyClientEnterView::from_query(
+                "notifycliententerview cfid=0 ctid=1 reasonid=0 clid=5 client_unique_identifier=a= client_nickname=<i>evil<\\/i>& client_database_id=5 client_country=<x client_type=0",
+            )
+            .unwrap(),
+            vec![],
+            vec!["⭐ priority speaker".to_string()],
+            ChannelLabel::resolve(&HashMap::new(), 1, false),
+        );
+        let message = enter.render(&Templates::default(), MessageFormat::Html, "");
+        assert!(message.contains("<b>&lt;i&gt;evil&lt;/i&gt;&amp;</b>"));
+        assert!(message.contains("[&lt;x]"));
+        let templates = Templates::inline(Some("{server_label} {clid} {badges}"), None).unwrap();
+        assert_eq!(
+            enter.render(&templates, MessageFormat::Html, "[Main]"),
+            "[Main] 5 ⭐ priority speaker"
+        );
+
+        let view = NotifyClientLeftView::from_query(
+            "notifyclientleftview cfid=1 ctid=0 reasonid=5 invokername=<b>admin invokeruid=x reasonmsg=<script>alert(1)<\\/script> clid=5",
+        )
+        .unwrap();
+        let left = TelegramData::Left(2, Utc::now(), view, "<u>".to_string(), None);
+        let message = left.render(&Templates::default(), MessageFormat::Html, "");
+        assert!(message.contains("<b>&lt;u&gt;</b>"));
+        assert!(message.contains("<b>&lt;b&gt;admin</b>"));
+        assert!(message.ends_with(": &lt;script&gt;alert(1)&lt;/script&gt;"));
+    }
+
+    #[tokio::test]
+    async fn test_staff_thread_events() {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(mock_server(
+            server,
+            vec![
+                "notifycliententerview cfid=0 ctid=1 reasonid=0 clid=5 client_unique_identifier=alice= client_nickname=alice client_database_id=5 client_country=JP client_type=0",
+                "notifycliententerview cfid=0 ctid=1 reasonid=0 clid=6 client_unique_identifier=ignored= client_nickname=bot client_database_id=6 client_country=JP client_type=0",
+                "notifycliententerview cfid=0 ctid=1 reasonid=0 clid=7 client_unique_identifier=ServerQuery client_nickname=query client_database_id=7 client_country= client_type=1",
+                "notifyclientleftview cfid=1 ctid=0 reasonid=8 reasonmsg=bye clid=6",
+                "notifyclientleftview cfid=1 ctid=0 reasonid=8 reasonmsg=bye clid=5",
+            ],
+        ));
+        let conn = SocketConn::from_stream(client).await.unwrap();
+        let (exit_sender, exit_receiver) = watch::channel(false);
+        let (output, mut events) = mpsc::channel(16);
+        let (_command_sender, command_receiver) = mpsc::channel(1);
+        let config: Config = toml::from_str(
+            "[server]\nignore_user = [\"ignored=\"]\n[misc]\nempty_uid = \"notify\"\nreconnect = false\nannounce_lifecycle = false\n[raw_query]\nuser = \"serveradmin\"\npassword = \"secret\"\n",
+        )
+        .unwrap();
+        let staff = tokio::spawn(staff_thread(
+            conn,
+            None,
+            exit_receiver,
+            StaffContext {
+                sender: EventSender {
+                    outputs: vec![output],
+                    database: None,
+                    policy: SinkPolicy::Parallel,
+                },
+                command_receiver,
+                pause_control: Arc::new(PauseControl::default()),
+                healthy: Arc::new(AtomicBool::new(false)),
+                sequence: EventSequence::default(),
+                client_map: Default::default(),
+                channel_map: Default::default(),
+                raw_lines: None,
+                metrics: Arc::new(Metrics::new().unwrap()),
+            },
+            StaffOptions::new(&config, config.server()).unwrap(),
+        ));
+
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while let Some(event) = events.recv().await {
+                match event {
+                    TelegramData::Terminate => break,
+                    TelegramData::Left(..) => {
+                        received.push(event);
+                        exit_sender.send(true).unwrap();
+                    }
+                    event => received.push(event),
+                }
+            }
+        })
+        .await
+        .expect("staff thread did not emit expected events in time");
+        staff.await.unwrap().unwrap();
+
+        assert_eq!(received.len(), 3);
+        assert!(matches!(
+            received[0],
+            TelegramData::ServerStatus(_, _, true, _)
+        ));
+        assert!(
+            matches!(&received[1], TelegramData::Enter(_, _, view, ..) if view.client_id() == 5)
+        );
+        assert!(
+            matches!(&received[2], TelegramData::Left(_, _, view, ..) if view.client_id() == 5)
+        );
+    }
+}
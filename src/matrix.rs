@@ -0,0 +1,134 @@
+use crate::datastructures::config::ServerStatus as ServerStatusConfig;
+use crate::format::MessageFormat;
+use crate::sanitize::NicknameSanitize;
+use crate::template::SharedTemplates;
+use crate::{display_time, OutputOptions, TelegramData};
+use log::{debug, error, info, warn};
+use reqwest::Url;
+use serde_json::json;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Render event in given format, `None` if event should not be sent
+fn render(
+    cmd: &TelegramData,
+    format: MessageFormat,
+    sanitize: &NicknameSanitize,
+    decoration: &Option<String>,
+    server_label: &str,
+    templates: &SharedTemplates,
+    status: &ServerStatusConfig,
+) -> Option<String> {
+    let line = match cmd {
+        TelegramData::ServerStatus(..) if !status.announce() => return None,
+        TelegramData::ServerStatus(_, time, _, text) => format!(
+            "{} {}",
+            format.escape(&format!("[{}]", display_time(*time))),
+            text
+        ),
+        _ => {
+            cmd.clone()
+                .sanitized(sanitize)
+                .render(&templates.read().unwrap(), format, server_label)
+        }
+    };
+    Some(match decoration {
+        Some(decoration) => format!("{} {}", decoration, line),
+        None => line,
+    })
+}
+
+pub async fn matrix_thread(
+    homeserver: String,
+    access_token: String,
+    room_id: String,
+    output: OutputOptions,
+    mut receiver: mpsc::Receiver<TelegramData>,
+) -> anyhow::Result<()> {
+    let OutputOptions {
+        sanitize,
+        decoration,
+        server_label,
+        templates,
+        is_leader,
+        status,
+        dry_run,
+    } = output;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let base = Url::parse(&homeserver)
+        .map_err(|e| anyhow::anyhow!("Got error while parse matrix homeserver: {:?}", e))?;
+    // Transaction id must be unique per access token, so include startup time
+    let started = chrono::Utc::now().timestamp_millis();
+    let mut transaction = 0u64;
+    while let Some(cmd) = receiver.recv().await {
+        if let TelegramData::Terminate = cmd {
+            break;
+        }
+        if !is_leader.load(Ordering::Relaxed) {
+            debug!("Standby instance, drop event #{:?}", cmd.sequence());
+            continue;
+        }
+        let render = |format| {
+            render(
+                &cmd,
+                format,
+                &sanitize,
+                &decoration,
+                &server_label,
+                &templates,
+                &status,
+            )
+        };
+        let (body, formatted_body) =
+            match (render(MessageFormat::None), render(MessageFormat::Html)) {
+                (Some(body), Some(formatted_body)) => (body, formatted_body),
+                _ => continue,
+            };
+        if dry_run {
+            info!("[dry run] Send matrix message: {}", formatted_body);
+            continue;
+        }
+        transaction += 1;
+        let mut url = base.clone();
+        match url.path_segments_mut() {
+            Ok(mut segments) => {
+                segments.pop_if_empty().extend([
+                    "_matrix",
+                    "client",
+                    "v3",
+                    "rooms",
+                    &room_id,
+                    "send",
+                    "m.room.message",
+                    &format!("{}-{}", started, transaction),
+                ]);
+            }
+            Err(_) => return Err(anyhow::anyhow!("Invalid matrix homeserver: {}", homeserver)),
+        }
+        let message = json!({
+            "msgtype": "m.text",
+            "body": body,
+            "format": "org.matrix.custom.html",
+            "formatted_body": formatted_body,
+        });
+        match client
+            .put(url)
+            .bearer_auth(&access_token)
+            .json(&message)
+            .send()
+            .await
+        {
+            Ok(response) if !response.status().is_success() => warn!(
+                "Matrix homeserver got unexpected status: {}",
+                response.status()
+            ),
+            Ok(_) => {}
+            Err(e) => error!("Got error while send matrix message: {:?}", e),
+        }
+    }
+    debug!("Matrix daemon exiting...");
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use crate::http::{not_found, serve};
+use log::{debug, warn};
+use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub struct Metrics {
+    registry: Registry,
+    clients_joined: IntCounter,
+    clients_left: IntCounter,
+    telegram_send_failures: IntCounter,
+    clients_online: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new();
+        let clients_joined =
+            IntCounter::new("ts_clients_joined_total", "Total number of joined clients")?;
+        let clients_left =
+            IntCounter::new("ts_clients_left_total", "Total number of left clients")?;
+        let telegram_send_failures = IntCounter::new(
+            "ts_telegram_send_failures_total",
+            "Total number of failed telegram send requests",
+        )?;
+        let clients_online = IntGauge::new(
+            "ts_clients_online",
+            "Number of online clients exclude server query",
+        )?;
+        registry.register(Box::new(clients_joined.clone()))?;
+        registry.register(Box::new(clients_left.clone()))?;
+        registry.register(Box::new(telegram_send_failures.clone()))?;
+        registry.register(Box::new(clients_online.clone()))?;
+        Ok(Self {
+            registry,
+            clients_joined,
+            clients_left,
+            telegram_send_failures,
+            clients_online,
+        })
+    }
+
+    pub fn clients_joined(&self) -> &IntCounter {
+        &self.clients_joined
+    }
+    pub fn clients_left(&self) -> &IntCounter {
+        &self.clients_left
+    }
+    pub fn telegram_send_failures(&self) -> &IntCounter {
+        &self.telegram_send_failures
+    }
+    pub fn clients_online(&self) -> &IntGauge {
+        &self.clients_online
+    }
+
+    /// Encode all metrics in prometheus text format
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+pub async fn pushgateway_thread(
+    metrics: Arc<Metrics>,
+    url: String,
+    job: String,
+    interval: u64,
+) -> anyhow::Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let url = format!("{}/metrics/job/{}", url.trim_end_matches('/'), job);
+    loop {
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        let body = match metrics.encode() {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Got error while encode metrics: {:?}", e);
+                continue;
+            }
+        };
+        match client.put(&url).body(body).send().await {
+            Ok(response) if !response.status().is_success() => {
+                warn!("Pushgateway got unexpected status: {}", response.status())
+            }
+            Ok(_) => debug!("Metrics pushed"),
+            Err(e) => warn!("Got error while push metrics: {:?}", e),
+        }
+    }
+}
+
+/// Serve metrics in prometheus text format at `/metrics`
+pub async fn server_thread(metrics: Arc<Metrics>, bind: String) -> anyhow::Result<()> {
+    serve("Metrics", bind, move |path| {
+        let metrics = metrics.clone();
+        async move {
+            Ok(match path.as_str() {
+                "/metrics" => (
+                    "200 OK",
+                    TextEncoder::new().format_type().to_string(),
+                    metrics.encode()?,
+                ),
+                _ => not_found(),
+            })
+        }
+    })
+    .await
+}
@@ -0,0 +1,119 @@
+use crate::datastructures::Client;
+use crate::roster::OnlineClient;
+use crate::socketlib::escape;
+use std::collections::HashMap;
+
+/// Compare `clientlist` result with tracked clients, and synthesize notify lines of changes,
+/// so polled changes go through the same path as registered notifications.
+pub fn diff_clients(clients: &[Client], tracked: &HashMap<i64, OnlineClient>) -> Vec<String> {
+    let clients = clients
+        .iter()
+        .filter(|client| client.client_type() == 0)
+        .map(|client| (client.client_id(), client))
+        .collect::<HashMap<_, _>>();
+    let mut lines = Vec::new();
+    let mut left = tracked
+        .iter()
+        .filter(|(client_id, online)| {
+            // Client id may be reused by another client between two polls
+            !matches!(clients.get(client_id), Some(client) if client.client_unique_identifier() == online.unique_identifier())
+        })
+        .map(|(client_id, online)| (*client_id, online.channel_id()))
+        .collect::<Vec<_>>();
+    left.sort_unstable();
+    for (client_id, channel_id) in &left {
+        lines.push(format!(
+            "notifyclientleftview cfid={} ctid=0 reasonid=8 clid={}",
+            channel_id, client_id
+        ));
+    }
+    let mut client_ids = clients.keys().copied().collect::<Vec<_>>();
+    client_ids.sort_unstable();
+    for client_id in client_ids {
+        let client = clients[&client_id];
+        match tracked.get(&client_id) {
+            Some(online) if !left.iter().any(|(id, _)| *id == client_id) => {
+                if online.channel_id() != client.channel_id() {
+                    lines.push(format!(
+                        "notifyclientmoved ctid={} reasonid=0 clid={}",
+                        client.channel_id(),
+                        client_id
+                    ));
+                }
+                if online.nickname() != client.client_nickname() {
+                    lines.push(format!(
+                        "notifyclientupdated clid={} client_nickname={}",
+                        client_id,
+                        escape(client.client_nickname())
+                    ));
+                }
+            }
+            _ => lines.push(format!(
+                "notifycliententerview cfid=0 ctid={} reasonid=0 clid={} client_unique_identifier={} client_nickname={} client_database_id={} client_country={} client_servergroups={} client_type=0",
+                client.channel_id(),
+                client_id,
+                escape(client.client_unique_identifier()),
+                escape(client.client_nickname()),
+                client.client_database_id(),
+                escape(client.client_country()),
+                client
+                    .server_groups()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )),
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::diff_clients;
+    use crate::datastructures::{Client, FromQueryString, NotifyClientEnterView};
+    use crate::roster::OnlineClient;
+    use std::collections::HashMap;
+
+    fn tracked(nickname: &str, unique_identifier: &str, channel_id: i64) -> OnlineClient {
+        OnlineClient::new(
+            nickname.to_string(),
+            unique_identifier.to_string(),
+            channel_id,
+            false,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_diff_clients() {
+        let clients = [
+            "clid=1 cid=1 client_database_id=1 client_nickname=stay client_type=0 client_unique_identifier=a",
+            "clid=2 cid=2 client_database_id=2 client_nickname=renamed client_type=0 client_unique_identifier=b",
+            "clid=4 cid=1 client_database_id=4 client_nickname=new\\sone client_type=0 client_unique_identifier=d client_country=JP client_servergroups=6,8",
+            "clid=5 cid=1 client_database_id=5 client_nickname=query client_type=1 client_unique_identifier=serveradmin",
+        ]
+        .iter()
+        .map(|line| Client::from_query(line).unwrap())
+        .collect::<Vec<_>>();
+        let tracked = HashMap::from([
+            (1, tracked("stay", "a", 1)),
+            (2, tracked("old", "b", 1)),
+            (3, tracked("gone", "c", 1)),
+        ]);
+        let lines = diff_clients(&clients, &tracked);
+        assert_eq!(
+            lines[..3],
+            [
+                "notifyclientleftview cfid=1 ctid=0 reasonid=8 clid=3",
+                "notifyclientmoved ctid=2 reasonid=0 clid=2",
+                "notifyclientupdated clid=2 client_nickname=renamed",
+            ]
+        );
+        assert_eq!(lines.len(), 4);
+        let view = NotifyClientEnterView::from_query(&lines[3]).unwrap();
+        assert_eq!(view.client_nickname(), "new one");
+        assert_eq!(view.client_country(), "JP");
+        assert_eq!(view.server_groups(), vec![6, 8]);
+    }
+}
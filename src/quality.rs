@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Skip clients which sent fewer packets than this
+pub const MIN_PACKETS_SENT: u64 = 500;
+
+/// Periodically check clients' packet loss, alert at most once per `cooldown` for each client.
+pub struct PacketLossMonitor {
+    threshold: f64,
+    interval: Duration,
+    cooldown: Duration,
+    last_check: Instant,
+    last_alert: HashMap<i64, Instant>,
+}
+
+impl PacketLossMonitor {
+    /// `threshold` is in percent
+    pub fn new(threshold: f64, interval: Duration, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            interval,
+            cooldown,
+            last_check: Instant::now(),
+            last_alert: Default::default(),
+        }
+    }
+
+    /// Return true if check interval elapsed since last check, and reset the timer.
+    pub fn due(&mut self) -> bool {
+        if self.last_check.elapsed() < self.interval {
+            return false;
+        }
+        self.last_check = Instant::now();
+        true
+    }
+
+    /// Forget clients which are no longer online.
+    pub fn retain(&mut self, online: impl Fn(i64) -> bool) {
+        self.last_alert.retain(|client_id, _| online(*client_id));
+    }
+
+    pub fn should_alert(&mut self, client_id: i64, packet_loss: f64) -> bool {
+        if packet_loss < self.threshold {
+            return false;
+        }
+        let now = Instant::now();
+        match self.last_alert.get(&client_id) {
+            Some(last) if now.duration_since(*last) < self.cooldown => false,
+            _ => {
+                self.last_alert.insert(client_id, now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PacketLossMonitor;
+    use std::time::Duration;
+
+    #[test]
+    fn test_should_alert() {
+        let mut monitor =
+            PacketLossMonitor::new(5.0, Duration::from_secs(60), Duration::from_secs(3600));
+        assert!(!monitor.should_alert(1, 4.9));
+        assert!(monitor.should_alert(1, 8.0));
+        assert!(!monitor.should_alert(1, 9.0));
+        assert!(monitor.should_alert(2, 5.0));
+        monitor.retain(|client_id| client_id != 1);
+        assert!(monitor.should_alert(1, 8.0));
+    }
+}
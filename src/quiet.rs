@@ -0,0 +1,57 @@
+use anyhow::anyhow;
+use chrono::{Local, NaiveTime};
+
+/// Daily local time window in which telegram notifications are suppressed.
+#[derive(Clone, Copy, Debug)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl QuietHours {
+    /// Parse `HH:MM` start and end time
+    pub fn parse(start: &str, end: &str) -> anyhow::Result<Self> {
+        let parse = |s: &str| {
+            NaiveTime::parse_from_str(s.trim(), "%H:%M")
+                .map_err(|e| anyhow!("Got error while parse quiet hours {:?}: {:?}", s, e))
+        };
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+
+    /// Window may span midnight, e.g. 23:00 to 07:00
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    pub fn is_quiet_now(&self) -> bool {
+        self.contains(Local::now().time())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::QuietHours;
+    use chrono::NaiveTime;
+
+    #[test]
+    fn test_quiet_hours() {
+        let time = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let overnight = QuietHours::parse("23:00", "07:00").unwrap();
+        assert!(overnight.contains(time(23, 30)));
+        assert!(overnight.contains(time(3, 0)));
+        assert!(!overnight.contains(time(7, 0)));
+        assert!(!overnight.contains(time(12, 0)));
+        let daytime = QuietHours::parse("09:00", "17:00").unwrap();
+        assert!(daytime.contains(time(9, 0)));
+        assert!(!daytime.contains(time(17, 0)));
+        assert!(!daytime.contains(time(20, 0)));
+        assert!(QuietHours::parse("25:00", "07:00").is_err());
+    }
+}
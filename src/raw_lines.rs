@@ -0,0 +1,70 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+pub const MAX_CAPACITY: usize = 1000;
+const SECRET_KEYS: [&str; 3] = ["password", "apikey", "token"];
+
+/// Ring buffer of last received raw protocol lines, for debugging parse failure.
+#[derive(Debug)]
+pub struct RawLineBuffer {
+    capacity: usize,
+    lines: VecDeque<String>,
+}
+
+pub type SharedRawLines = Arc<Mutex<RawLineBuffer>>;
+
+impl RawLineBuffer {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.min(MAX_CAPACITY);
+        Self {
+            capacity,
+            lines: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, line: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(redact(line));
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.iter().cloned().collect()
+    }
+}
+
+fn redact(line: &str) -> String {
+    line.split(' ')
+        .map(|element| match element.split_once('=') {
+            Some((key, _)) if SECRET_KEYS.iter().any(|secret| key.contains(secret)) => {
+                format!("{}=<redacted>", key)
+            }
+            _ => element.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::RawLineBuffer;
+
+    #[test]
+    fn test_buffer() {
+        let mut buffer = RawLineBuffer::new(2);
+        buffer.push("line1");
+        buffer.push("notifyserveredited client_login_password=secret clid=1");
+        buffer.push("line3");
+        assert_eq!(
+            buffer.lines(),
+            vec![
+                "notifyserveredited client_login_password=<redacted> clid=1".to_string(),
+                "line3".to_string()
+            ]
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// What to do with held left event when client enters again
+#[derive(Debug, Eq, PartialEq)]
+pub enum Rejoin<T> {
+    /// Client left within grace period, both left and enter are suppressed
+    Suppressed,
+    /// Grace period passed but left event was not flushed yet, send it before enter
+    Expired(T),
+    NotHeld,
+}
+
+/// Hold left events for a while, so a quick rejoin suppresses both left and enter.
+#[derive(Debug)]
+pub struct RejoinDebounce<T> {
+    grace: Duration,
+    pending: HashMap<i64, (Instant, T)>,
+}
+
+impl<T> RejoinDebounce<T> {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Hold left event of client database id until grace period passed
+    pub fn hold(&mut self, database_id: i64, left: T) {
+        self.pending.insert(database_id, (Instant::now(), left));
+    }
+
+    /// Take held left event of client which enters again
+    pub fn rejoin(&mut self, database_id: i64) -> Rejoin<T> {
+        match self.pending.remove(&database_id) {
+            Some((since, _)) if since.elapsed() < self.grace => Rejoin::Suppressed,
+            Some((_, left)) => Rejoin::Expired(left),
+            None => Rejoin::NotHeld,
+        }
+    }
+
+    /// Take left events whose grace period passed
+    pub fn expired(&mut self) -> Vec<T> {
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, (since, _))| since.elapsed() >= self.grace)
+            .map(|(database_id, _)| *database_id)
+            .collect::<Vec<_>>();
+        let mut ret = expired
+            .into_iter()
+            .filter_map(|database_id| self.pending.remove(&database_id))
+            .collect::<Vec<_>>();
+        ret.sort_by_key(|(since, _)| *since);
+        ret.into_iter().map(|(_, left)| left).collect()
+    }
+
+    /// Take all held left events, used before exit
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut ret = self.pending.drain().map(|(_, x)| x).collect::<Vec<_>>();
+        ret.sort_by_key(|(since, _)| *since);
+        ret.into_iter().map(|(_, left)| left).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Rejoin, RejoinDebounce};
+    use std::time::Duration;
+
+    #[test]
+    fn test_rejoin() {
+        let mut debounce = RejoinDebounce::new(Duration::from_secs(60));
+        debounce.hold(1, "left 1");
+        debounce.hold(2, "left 2");
+        assert_eq!(debounce.rejoin(1), Rejoin::Suppressed);
+        assert_eq!(debounce.rejoin(1), Rejoin::NotHeld);
+        assert!(debounce.expired().is_empty());
+        assert_eq!(debounce.drain(), vec!["left 2"]);
+
+        let mut debounce = RejoinDebounce::new(Duration::ZERO);
+        debounce.hold(1, "left 1");
+        assert_eq!(debounce.expired(), vec!["left 1"]);
+        assert_eq!(debounce.rejoin(1), Rejoin::NotHeld);
+
+        // Enter arrived after grace period but before next flush, left is not lost
+        debounce.hold(2, "left 2");
+        assert_eq!(debounce.rejoin(2), Rejoin::Expired("left 2"));
+        assert!(debounce.expired().is_empty());
+    }
+}
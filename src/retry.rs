@@ -0,0 +1,205 @@
+use log::{error, warn};
+use serde_derive::Deserialize;
+use std::io;
+use std::time::Duration;
+
+/// Category of connection error, used to decide whether to retry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorCategory {
+    Refused,
+    Reset,
+    TimedOut,
+    Unreachable,
+    Dns,
+    Other,
+}
+
+impl ErrorCategory {
+    pub fn classify(error: &anyhow::Error) -> Self {
+        match error.chain().find_map(|e| e.downcast_ref::<io::Error>()) {
+            Some(e) => Self::from(e),
+            None => Self::Other,
+        }
+    }
+}
+
+impl From<&io::Error> for ErrorCategory {
+    fn from(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => Self::Refused,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => Self::Reset,
+            io::ErrorKind::TimedOut => Self::TimedOut,
+            // HostUnreachable and NetworkUnreachable are not stable yet, match their name instead
+            kind if matches!(
+                format!("{:?}", kind).as_str(),
+                "HostUnreachable" | "NetworkUnreachable"
+            ) =>
+            {
+                Self::Unreachable
+            }
+            // getaddrinfo failure has no dedicated kind
+            _ if e.to_string().contains("failed to lookup address") => Self::Dns,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryAction {
+    Retry,
+    Fail,
+}
+
+/// Per category retry behavior, server down is retried, configuration errors fail fast by default.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RetryPolicy {
+    refused: Option<RetryAction>,
+    reset: Option<RetryAction>,
+    timed_out: Option<RetryAction>,
+    unreachable: Option<RetryAction>,
+    dns: Option<RetryAction>,
+    other: Option<RetryAction>,
+    delay: Option<u64>,
+}
+
+impl RetryPolicy {
+    pub fn action(&self, category: ErrorCategory) -> RetryAction {
+        match category {
+            ErrorCategory::Refused => self.refused.unwrap_or(RetryAction::Retry),
+            ErrorCategory::Reset => self.reset.unwrap_or(RetryAction::Retry),
+            ErrorCategory::TimedOut => self.timed_out.unwrap_or(RetryAction::Retry),
+            ErrorCategory::Unreachable => self.unreachable.unwrap_or(RetryAction::Fail),
+            ErrorCategory::Dns => self.dns.unwrap_or(RetryAction::Fail),
+            ErrorCategory::Other => self.other.unwrap_or(RetryAction::Fail),
+        }
+    }
+
+    pub fn delay(&self) -> Duration {
+        Duration::from_secs(self.delay.unwrap_or(5))
+    }
+
+    /// Return Ok(()) if error should be retried after waiting, otherwise give the error back.
+    pub async fn check(&self, error: anyhow::Error) -> anyhow::Result<()> {
+        let category = ErrorCategory::classify(&error);
+        match self.action(category) {
+            RetryAction::Retry => {
+                warn!(
+                    "Connection failed ({:?}), retry in {:?}: {:?}",
+                    category,
+                    self.delay(),
+                    error
+                );
+                tokio::time::sleep(self.delay()).await;
+                Ok(())
+            }
+            RetryAction::Fail => {
+                error!("Connection failed ({:?}), not retrying", category);
+                Err(error)
+            }
+        }
+    }
+}
+
+/// Exponential backoff delays, doubled on each call until `max`.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            current: base,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.base;
+    }
+}
+
+/// Space out requests, so at most `per_second` requests are sent in one second.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    next: Option<tokio::time::Instant>,
+}
+
+impl RateLimiter {
+    /// Zero means unlimited
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            interval: if per_second == 0 {
+                Duration::ZERO
+            } else {
+                Duration::from_secs(1) / per_second
+            },
+            next: None,
+        }
+    }
+
+    /// Wait until next request is allowed
+    pub async fn wait(&mut self) {
+        if let Some(next) = self.next {
+            tokio::time::sleep_until(next).await;
+        }
+        self.next = Some(tokio::time::Instant::now() + self.interval);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backoff, ErrorCategory, RateLimiter, RetryAction, RetryPolicy};
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    fn test_classify() {
+        let error = anyhow::Error::new(io::Error::from(io::ErrorKind::ConnectionRefused))
+            .context("Got error while connect");
+        assert_eq!(ErrorCategory::classify(&error), ErrorCategory::Refused);
+        assert_eq!(
+            ErrorCategory::classify(&anyhow::anyhow!("Login failed")),
+            ErrorCategory::Other
+        );
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.action(ErrorCategory::Refused), RetryAction::Retry);
+        assert_eq!(policy.action(ErrorCategory::Dns), RetryAction::Fail);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(4));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        tokio::time::pause();
+        let mut limiter = RateLimiter::new(4);
+        let start = tokio::time::Instant::now();
+        for _ in 0..5 {
+            limiter.wait().await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,334 @@
+use crate::datastructures::Channel;
+use crate::humanize::relative_time;
+use crate::sanitize::NicknameSanitize;
+use chrono::{DateTime, Local};
+use serde_derive::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+const MESSAGE_LENGTH_LIMIT: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub struct OnlineClient {
+    nickname: String,
+    unique_identifier: String,
+    database_id: i64,
+    channel_id: i64,
+    is_query: bool,
+    connected_at: Option<DateTime<Local>>,
+    server_groups: Vec<i64>,
+    country: String,
+}
+
+impl OnlineClient {
+    pub fn new(
+        nickname: String,
+        unique_identifier: String,
+        channel_id: i64,
+        is_query: bool,
+        connected_at: Option<DateTime<Local>>,
+    ) -> Self {
+        Self {
+            nickname,
+            unique_identifier,
+            database_id: 0,
+            channel_id,
+            is_query,
+            connected_at,
+            server_groups: Vec::new(),
+            country: String::new(),
+        }
+    }
+    pub fn with_country(mut self, country: &str) -> Self {
+        self.country = country.to_string();
+        self
+    }
+    pub fn with_server_groups(mut self, server_groups: Vec<i64>) -> Self {
+        self.server_groups = server_groups;
+        self
+    }
+    pub fn with_database_id(mut self, database_id: i64) -> Self {
+        self.database_id = database_id;
+        self
+    }
+    pub fn nickname(&self) -> &str {
+        &self.nickname
+    }
+    pub fn set_nickname(&mut self, nickname: String) {
+        self.nickname = nickname;
+    }
+    pub fn unique_identifier(&self) -> &str {
+        &self.unique_identifier
+    }
+    /// Zero if unknown
+    pub fn database_id(&self) -> i64 {
+        self.database_id
+    }
+    pub fn channel_id(&self) -> i64 {
+        self.channel_id
+    }
+    pub fn set_channel_id(&mut self, channel_id: i64) {
+        self.channel_id = channel_id;
+    }
+    pub fn is_query(&self) -> bool {
+        self.is_query
+    }
+    pub fn connected_at(&self) -> Option<DateTime<Local>> {
+        self.connected_at
+    }
+    pub fn server_groups(&self) -> &[i64] {
+        &self.server_groups
+    }
+    /// Empty if unknown
+    pub fn country(&self) -> &str {
+        &self.country
+    }
+}
+
+pub type ClientMap = Arc<RwLock<HashMap<i64, OnlineClient>>>;
+pub type ChannelMap = Arc<RwLock<HashMap<i64, Channel>>>;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RosterFormat {
+    #[default]
+    List,
+    Compact,
+}
+
+impl FromStr for RosterFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "list" => Ok(Self::List),
+            "compact" => Ok(Self::Compact),
+            _ => Err(anyhow::anyhow!("Unknown roster format: {}", s)),
+        }
+    }
+}
+
+/// Find all sessions of specify unique identifier, sorted by client id.
+pub fn sessions_of<'a>(
+    clients: &'a HashMap<i64, OnlineClient>,
+    unique_identifier: &str,
+) -> Vec<(i64, &'a OnlineClient)> {
+    if unique_identifier.is_empty() {
+        return vec![];
+    }
+    let mut sessions = clients
+        .iter()
+        .filter(|(_, client)| client.unique_identifier() == unique_identifier)
+        .map(|(client_id, client)| (*client_id, client))
+        .collect::<Vec<_>>();
+    sessions.sort_unstable_by_key(|(client_id, _)| *client_id);
+    sessions
+}
+
+/// Format online clients grouped by channel, split into messages at channel group boundaries.
+pub fn format_roster(
+    clients: &HashMap<i64, OnlineClient>,
+    channels: &HashMap<i64, Channel>,
+    format: RosterFormat,
+    sanitize: &NicknameSanitize,
+    relative: bool,
+) -> Vec<String> {
+    let now = Local::now();
+    let mut groups: BTreeMap<i64, Vec<String>> = BTreeMap::new();
+    for client in clients.values().filter(|client| !client.is_query()) {
+        let nickname = sanitize.apply(client.nickname());
+        let entry = match (format, client.connected_at()) {
+            (RosterFormat::List, Some(connected_at)) if relative => format!(
+                "{} (joined {})",
+                nickname,
+                relative_time((now - connected_at).to_std().unwrap_or_default())
+            ),
+            (RosterFormat::List, Some(connected_at)) => format!(
+                "{} (joined {})",
+                nickname,
+                connected_at.format("%Y-%m-%d %H:%M:%S")
+            ),
+            _ => nickname,
+        };
+        groups.entry(client.channel_id()).or_default().push(entry);
+    }
+    let total: usize = groups.values().map(|group| group.len()).sum();
+
+    let (separator, groups) = match format {
+        RosterFormat::List => (
+            "\n\n",
+            groups
+                .into_iter()
+                .map(|(channel_id, mut nicknames)| {
+                    nicknames.sort_unstable();
+                    format!(
+                        "{}:\n{}",
+                        channel_label(channels, channel_id),
+                        nicknames
+                            .iter()
+                            .map(|nickname| format!("- {}", nickname))
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    )
+                })
+                .collect::<Vec<_>>(),
+        ),
+        RosterFormat::Compact => (
+            " | ",
+            groups
+                .into_iter()
+                .map(|(channel_id, mut nicknames)| {
+                    nicknames.sort_unstable();
+                    format!(
+                        "{}: {}",
+                        channel_label(channels, channel_id),
+                        nicknames.join(", ")
+                    )
+                })
+                .collect::<Vec<_>>(),
+        ),
+    };
+
+    let mut messages = Vec::new();
+    let mut current = format!("{} client(s) online\n", total);
+    let mut is_first = true;
+    for group in groups {
+        if !is_first && current.len() + separator.len() + group.len() > MESSAGE_LENGTH_LIMIT {
+            messages.push(current);
+            current = String::new();
+            is_first = true;
+        }
+        if !is_first {
+            current.push_str(separator);
+        }
+        current.push_str(&group);
+        is_first = false;
+    }
+    messages.push(current);
+    messages
+}
+
+fn channel_label(channels: &HashMap<i64, Channel>, channel_id: i64) -> String {
+    channels
+        .get(&channel_id)
+        .map(|channel| channel.channel_name().to_string())
+        .unwrap_or_else(|| format!("#{}", channel_id))
+}
+
+/// Build full channel path like "Gaming / Valorant / Team A" by walking parent chain.
+///
+/// Walk stops at missing parent (stale cache) or loop, `None` if channel itself is unknown.
+pub fn channel_path(channels: &HashMap<i64, Channel>, channel_id: i64) -> Option<String> {
+    let mut names = vec![channels.get(&channel_id)?.channel_name()];
+    let mut current = channels.get(&channel_id)?.parent_id();
+    while current != 0 && names.len() <= channels.len() {
+        match channels.get(&current) {
+            Some(channel) => {
+                names.push(channel.channel_name());
+                current = channel.parent_id();
+            }
+            None => break,
+        }
+    }
+    names.reverse();
+    Some(names.join(" / "))
+}
+
+/// Channel name and path resolved when event happened.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelLabel {
+    name: String,
+    path: String,
+    show_path: bool,
+}
+
+impl ChannelLabel {
+    pub fn resolve(channels: &HashMap<i64, Channel>, channel_id: i64, show_path: bool) -> Self {
+        Self {
+            name: channel_label(channels, channel_id),
+            path: channel_path(channels, channel_id).unwrap_or_else(|| format!("#{}", channel_id)),
+            show_path,
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    /// Whether path should be shown in default message format
+    pub fn show_path(&self) -> bool {
+        self.show_path
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{channel_path, format_roster, OnlineClient, RosterFormat};
+    use crate::datastructures::Channel;
+    use crate::sanitize::NicknameSanitize;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_compact_format() {
+        let clients = HashMap::from([
+            (
+                1,
+                OnlineClient::new("B".to_string(), "b".to_string(), 1, false, None),
+            ),
+            (
+                2,
+                OnlineClient::new("A".to_string(), "a".to_string(), 1, false, None),
+            ),
+            (
+                3,
+                OnlineClient::new("D".to_string(), "d".to_string(), 2, false, None),
+            ),
+            (
+                4,
+                OnlineClient::new(
+                    "serveradmin".to_string(),
+                    "ServerQuery".to_string(),
+                    1,
+                    true,
+                    None,
+                ),
+            ),
+        ]);
+        let channels = HashMap::from([
+            (1, Channel::new(1, 0, "Lobby")),
+            (2, Channel::new(2, 0, "Gaming")),
+        ]);
+        assert_eq!(
+            format_roster(
+                &clients,
+                &channels,
+                RosterFormat::Compact,
+                &NicknameSanitize::default(),
+                false,
+            ),
+            vec!["3 client(s) online\nLobby: A, B | Gaming: D".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_channel_path() {
+        let channels = HashMap::from([
+            (1, Channel::new(1, 0, "Gaming")),
+            (2, Channel::new(2, 1, "Valorant")),
+            (3, Channel::new(3, 2, "Team A")),
+            (4, Channel::new(4, 9, "Orphan")),
+            (5, Channel::new(5, 6, "Loop A")),
+            (6, Channel::new(6, 5, "Loop B")),
+        ]);
+        assert_eq!(
+            channel_path(&channels, 3).as_deref(),
+            Some("Gaming / Valorant / Team A")
+        );
+        assert_eq!(channel_path(&channels, 4).as_deref(), Some("Orphan"));
+        assert!(channel_path(&channels, 5).is_some());
+        assert_eq!(channel_path(&channels, 10), None);
+    }
+}
@@ -0,0 +1,74 @@
+use serde_derive::Deserialize;
+
+fn default_true() -> bool {
+    true
+}
+
+/// Which character categories should be stripped from displayed nickname.
+#[derive(Clone, Debug, Deserialize)]
+pub struct NicknameSanitize {
+    #[serde(default = "default_true")]
+    control: bool,
+    #[serde(default = "default_true")]
+    zero_width: bool,
+    #[serde(default = "default_true")]
+    bidi: bool,
+}
+
+impl Default for NicknameSanitize {
+    fn default() -> Self {
+        Self {
+            control: true,
+            zero_width: true,
+            bidi: true,
+        }
+    }
+}
+
+fn is_zero_width(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200B}'..='\u{200D}' | '\u{2060}' | '\u{FEFF}' | '\u{180E}'
+    )
+}
+
+fn is_bidi_control(c: char) -> bool {
+    matches!(
+        c,
+        '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{200E}' | '\u{200F}' | '\u{061C}'
+    )
+}
+
+impl NicknameSanitize {
+    pub fn apply(&self, nickname: &str) -> String {
+        nickname
+            .chars()
+            .filter(|c| {
+                !((self.control && c.is_control())
+                    || (self.zero_width && is_zero_width(*c))
+                    || (self.bidi && is_bidi_control(*c)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NicknameSanitize;
+
+    #[test]
+    fn test_sanitize() {
+        let sanitize = NicknameSanitize::default();
+        assert_eq!(sanitize.apply("ad\u{200B}min"), "admin");
+        assert_eq!(sanitize.apply("\u{202E}nimda"), "nimda");
+        assert_eq!(sanitize.apply("line\nbreak"), "linebreak");
+        assert_eq!(sanitize.apply("普通の名前"), "普通の名前");
+
+        let sanitize = NicknameSanitize {
+            control: true,
+            zero_width: false,
+            bidi: true,
+        };
+        assert_eq!(sanitize.apply("ad\u{200B}min"), "ad\u{200B}min");
+    }
+}
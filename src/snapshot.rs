@@ -0,0 +1,137 @@
+use crate::roster::OnlineClient;
+use anyhow::anyhow;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Online client saved on shutdown, used to find out who joined or left while observer was down.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SnapshotClient {
+    pub client_id: i64,
+    pub nickname: String,
+    pub unique_identifier: String,
+    pub database_id: i64,
+    pub channel_id: i64,
+    pub is_query: bool,
+    /// Unix timestamp
+    pub connected_at: Option<i64>,
+    #[serde(default)]
+    pub server_groups: Vec<i64>,
+}
+
+impl SnapshotClient {
+    fn is_same_session(&self, client: &OnlineClient) -> bool {
+        // Client id is reused by server, unique identifier tells whether it is still the same client
+        self.unique_identifier == client.unique_identifier()
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    /// Unix timestamp when snapshot was written
+    saved_at: i64,
+    clients: Vec<SnapshotClient>,
+}
+
+impl Snapshot {
+    pub fn new(client_map: &HashMap<i64, OnlineClient>) -> Self {
+        Self {
+            saved_at: chrono::Utc::now().timestamp(),
+            clients: client_map
+                .iter()
+                .map(|(client_id, client)| SnapshotClient {
+                    client_id: *client_id,
+                    nickname: client.nickname().to_string(),
+                    unique_identifier: client.unique_identifier().to_string(),
+                    database_id: client.database_id(),
+                    channel_id: client.channel_id(),
+                    is_query: client.is_query(),
+                    connected_at: client.connected_at().map(|time| time.timestamp()),
+                    server_groups: client.server_groups().to_vec(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Return `None` if file does not exist
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Got error while read client snapshot: {:?}", e))?;
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| anyhow!("Got error while parse client snapshot: {:?}", e))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let content = serde_json::to_string(self)?;
+        // Write to temporary file first, so a crash never leaves half written snapshot
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, content)
+            .and_then(|_| std::fs::rename(&temp, path))
+            .map_err(|e| anyhow!("Got error while write client snapshot: {:?}", e))
+    }
+
+    pub fn saved_at(&self) -> i64 {
+        self.saved_at
+    }
+
+    /// Client ids which joined, and clients which left since snapshot was written
+    pub fn diff(self, current: &HashMap<i64, OnlineClient>) -> (Vec<i64>, Vec<SnapshotClient>) {
+        let mut joined = current.keys().copied().collect::<Vec<_>>();
+        let mut left = Vec::new();
+        for client in self.clients {
+            match current.get(&client.client_id) {
+                Some(online) if client.is_same_session(online) => {
+                    joined.retain(|client_id| *client_id != client.client_id)
+                }
+                _ => left.push(client),
+            }
+        }
+        joined.sort_unstable();
+        (joined, left)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Snapshot;
+    use crate::roster::OnlineClient;
+    use std::collections::HashMap;
+
+    fn client(nickname: &str, unique_identifier: &str) -> OnlineClient {
+        OnlineClient::new(
+            nickname.to_string(),
+            unique_identifier.to_string(),
+            1,
+            false,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = HashMap::from([
+            (1, client("stay", "a")),
+            (2, client("left", "b")),
+            (3, client("reused", "c")),
+        ]);
+        let snapshot = Snapshot::new(&before);
+        let current = HashMap::from([
+            (1, client("stay", "a")),
+            (3, client("new", "d")),
+            (4, client("joined", "e")),
+        ]);
+        let (joined, mut left) = snapshot.diff(&current);
+        assert_eq!(joined, vec![3, 4]);
+        left.sort_by_key(|client| client.client_id);
+        assert_eq!(
+            left.iter()
+                .map(|client| client.client_id)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+    }
+}
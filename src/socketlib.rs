@@ -1,15 +1,204 @@
-use crate::datastructures::{Client, QueryResult};
+use crate::datastructures::{
+    Channel, Client, ClientInfo, QueryError, QueryResult, ServerInfo, WhoAmI,
+};
 use crate::datastructures::{FromQueryString, QueryStatus};
+use crate::proxy::Proxy;
 use anyhow::anyhow;
+use encoding_rs::Encoding;
 use log::{error, warn};
+use serde_derive::Deserialize;
+use std::io;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
 
-const BUFFER_SIZE: usize = 512;
+/// Bytes requested from stream per read, framing does not depend on it
+const DEFAULT_READ_BUFFER_SIZE: usize = 4096;
+const DEFAULT_MAX_BUFFER_SIZE: usize = 1024 * 1024;
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Retry flooding command at most this many times
+const MAX_FLOOD_RETRY: u32 = 3;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventClass {
+    Server,
+    Channel,
+    TextServer,
+    TextChannel,
+    TextPrivate,
+}
+
+impl EventClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventClass::Server => "server",
+            EventClass::Channel => "channel",
+            EventClass::TextServer => "textserver",
+            EventClass::TextChannel => "textchannel",
+            EventClass::TextPrivate => "textprivate",
+        }
+    }
+}
+
+/// Plain TCP, TLS wrapped or in-memory stream (for tests)
+pub trait QueryStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> QueryStream for T {}
+
+/// How `authenticate` identifies the query session
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Credential {
+    Login { user: String, password: String },
+    ApiKey(String),
+}
 
 pub struct SocketConn {
-    conn: TcpStream,
+    conn: Box<dyn QueryStream>,
+    fallback_encoding: Option<&'static Encoding>,
+    max_buffer_size: usize,
+    read_buffer_size: usize,
+    /// Return `None` from `read_data` if nothing received in this period
+    read_timeout: Duration,
+    /// Minimum delay between two commands, avoid triggering anti-flood
+    command_interval: Duration,
+    last_command: Option<tokio::time::Instant>,
+    /// Set after oversized data discarded, skip bytes until next line terminator
+    resync: bool,
+    /// Received bytes after last line terminator, completed by following reads
+    partial: Vec<u8>,
+    /// Async notifies received while waiting command reply, returned by next `read_data`
+    pending_notifies: Vec<String>,
+    // Below fields are used to restore session in `reconnect`
+    address: (String, u16),
+    use_ssl: bool,
+    connect_timeout: Duration,
+    proxy: Option<Proxy>,
+    credentials: Option<Credential>,
+    server_id: Option<i64>,
+    registered_events: Vec<(EventClass, Option<i64>)>,
+}
+
+/// Escape string for ServerQuery command parameter
+pub fn escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => ret.push_str("\\\\"),
+            '/' => ret.push_str("\\/"),
+            ' ' => ret.push_str("\\s"),
+            '|' => ret.push_str("\\p"),
+            '\x07' => ret.push_str("\\a"),
+            '\x08' => ret.push_str("\\b"),
+            '\x0c' => ret.push_str("\\f"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            '\x0b' => ret.push_str("\\v"),
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// Reverse of [`escape`], unknown escape sequence is kept as is
+pub fn unescape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => ret.push('\\'),
+            Some('/') => ret.push('/'),
+            Some('s') => ret.push(' '),
+            Some('p') => ret.push('|'),
+            Some('a') => ret.push('\x07'),
+            Some('b') => ret.push('\x08'),
+            Some('f') => ret.push('\x0c'),
+            Some('n') => ret.push('\n'),
+            Some('r') => ret.push('\r'),
+            Some('t') => ret.push('\t'),
+            Some('v') => ret.push('\x0b'),
+            Some(c) => {
+                ret.push('\\');
+                ret.push(c);
+            }
+            None => ret.push('\\'),
+        }
+    }
+    ret
+}
+
+/// Strip brackets of IPv6 literal, e.g. `[::1]` to `::1`
+fn strip_brackets(server: &str) -> &str {
+    server
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(server)
+}
+
+/// Try every resolved address in turn, error of last attempt is returned if all failed
+async fn connect_tcp(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for address in tokio::net::lookup_host((host, port)).await? {
+        match TcpStream::connect(address).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                warn!("Got error while connect to {}: {:?}", address, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "failed to lookup address information: {} has no address",
+                host
+            ),
+        )
+    }))
+}
+
+/// Return wait time if reply is `error id=524` (flood ban), e.g. `extra_msg=please\swait\s3\sseconds`
+fn flood_wait(reply: &str) -> Option<Duration> {
+    let line = reply
+        .split("\n\r")
+        .find(|line| line.starts_with("error id=524 "))?;
+    let seconds = line
+        .split_once("wait\\s")
+        .and_then(|(_, rest)| {
+            rest.split(|c: char| !c.is_ascii_digit())
+                .next()?
+                .parse()
+                .ok()
+        })
+        .unwrap_or(1);
+    Some(Duration::from_secs(seconds))
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn rfind_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .rposition(|window| window == needle)
+}
+
+/// Banner sent by server right after connected
+fn is_banner(line: &str) -> bool {
+    line == "TS3" || line.starts_with("Welcome to the TeamSpeak 3")
 }
 
 impl SocketConn {
@@ -47,14 +236,70 @@ impl SocketConn {
         Ok(None)
     }
 
+    fn decode_bytes(&self, data: &[u8]) -> String {
+        if let Ok(s) = std::str::from_utf8(data) {
+            return s.to_string();
+        }
+        if let Some(encoding) = self.fallback_encoding {
+            let (s, _, had_errors) = encoding.decode(data);
+            if had_errors {
+                warn!(
+                    "Got malformed data while decode with {}, some characters are replaced",
+                    encoding.name()
+                );
+            }
+            return s.into_owned();
+        }
+        warn!("Got invalid UTF-8 data, some characters are replaced");
+        String::from_utf8_lossy(data).into_owned()
+    }
+
+    pub fn set_fallback_encoding(&mut self, encoding: Option<&'static Encoding>) {
+        self.fallback_encoding = encoding;
+    }
+
+    pub fn set_max_buffer_size(&mut self, size: usize) {
+        self.max_buffer_size = size;
+    }
+
+    pub fn set_read_buffer_size(&mut self, size: usize) {
+        self.read_buffer_size = size.max(1);
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = timeout;
+    }
+
+    pub fn set_command_interval(&mut self, interval: Duration) {
+        self.command_interval = interval;
+    }
+
+    /// Read data from server, buffered notifies are returned first.
     pub async fn read_data(&mut self) -> anyhow::Result<Option<String>> {
-        let mut buffer = [0u8; BUFFER_SIZE];
-        let mut ret = String::new();
+        if !self.pending_notifies.is_empty() {
+            let mut ret = self.pending_notifies.join("\n\r");
+            ret.push_str("\n\r");
+            self.pending_notifies.clear();
+            return Ok(Some(ret));
+        }
+        self.read_raw().await
+    }
+
+    /// Read until at least one complete line received, incomplete tail is kept for next read.
+    async fn read_raw(&mut self) -> anyhow::Result<Option<String>> {
+        let mut buffer = vec![0u8; self.read_buffer_size];
         loop {
+            if let Some(pos) = rfind_bytes(&self.partial, b"\n\r") {
+                let rest = self.partial.split_off(pos + 2);
+                let complete = std::mem::replace(&mut self.partial, rest);
+                return Ok(Some(self.decode_bytes(&complete)));
+            }
+
             let size = if let Ok(data) =
-                tokio::time::timeout(Duration::from_secs(2), self.conn.read(&mut buffer)).await
+                tokio::time::timeout(self.read_timeout, self.conn.read(&mut buffer)).await
             {
                 match data {
+                    Ok(0) => return Err(anyhow!("Connection closed by server")),
                     Ok(size) => size,
                     Err(e) => return Err(anyhow!("Got error while read data: {:?}", e)),
                 }
@@ -62,12 +307,30 @@ impl SocketConn {
                 return Ok(None);
             };
 
-            ret.push_str(&String::from_utf8_lossy(&buffer[..size]));
-            if size < BUFFER_SIZE || (ret.contains("error id=") && ret.ends_with("\n\r")) {
-                break;
+            let mut chunk = &buffer[..size];
+            if self.resync {
+                match find_bytes(chunk, b"\n\r") {
+                    Some(pos) => {
+                        chunk = &chunk[pos + 2..];
+                        self.resync = false;
+                    }
+                    None => continue,
+                }
+            }
+
+            self.partial.extend_from_slice(chunk);
+            if self.partial.len() > self.max_buffer_size
+                && find_bytes(&self.partial, b"\n\r").is_none()
+            {
+                error!(
+                    "Received more than {} bytes without terminator, discard and resync",
+                    self.max_buffer_size
+                );
+                self.partial.clear();
+                self.resync = true;
+                return Ok(Some(String::new()));
             }
         }
-        Ok(Some(ret))
     }
 
     pub async fn write_data(&mut self, payload: &str) -> anyhow::Result<()> {
@@ -89,11 +352,61 @@ impl SocketConn {
         Ok(())
     }
 
+    /// Wait until command interval elapsed since last command
+    async fn pace(&mut self) {
+        if let Some(last) = self.last_command {
+            tokio::time::sleep_until(last + self.command_interval).await;
+        }
+        self.last_command = Some(tokio::time::Instant::now());
+    }
+
+    /// Write command and read its reply, command is retried if server reports flooding.
     async fn write_and_read(&mut self, payload: &str) -> anyhow::Result<String> {
+        let mut attempt = 0;
+        loop {
+            self.pace().await;
+            let reply = self.write_and_read_once(payload).await?;
+            match flood_wait(&reply) {
+                Some(wait) if attempt < MAX_FLOOD_RETRY => {
+                    attempt += 1;
+                    warn!("Query is flooding, retry after {:?}", wait);
+                    tokio::time::sleep(wait).await;
+                }
+                _ => return Ok(reply),
+            }
+        }
+    }
+
+    /// Write command and read until its `error id=` line, notifies around the reply are buffered.
+    async fn write_and_read_once(&mut self, payload: &str) -> anyhow::Result<String> {
         self.write_data(payload).await?;
-        self.read_data()
-            .await?
-            .ok_or_else(|| anyhow!("Return data is None"))
+        let mut reply = String::new();
+        let mut complete = false;
+        while !complete {
+            let data = self
+                .read_raw()
+                .await?
+                .ok_or_else(|| anyhow!("Return data is None"))?;
+            // Keep going after the status line, notifies may follow it in the same chunk
+            for line in data.split("\n\r") {
+                let trimmed = line.trim();
+                if trimmed.is_empty() || is_banner(trimmed) {
+                    continue;
+                }
+                if trimmed.starts_with("notify") {
+                    self.pending_notifies.push(trimmed.to_string());
+                    continue;
+                }
+                if complete {
+                    warn!("Discard unexpected line after command reply: {}", trimmed);
+                    continue;
+                }
+                reply.push_str(trimmed);
+                reply.push_str("\n\r");
+                complete = trimmed.starts_with("error ");
+            }
+        }
+        Ok(reply)
     }
 
     async fn basic_operation(&mut self, payload: &str) -> QueryResult<()> {
@@ -122,47 +435,541 @@ impl SocketConn {
         //let status = status.ok_or_else(|| anyhow!("Can't find status line."))?;
     }
 
-    pub async fn connect(server: &str, port: u16) -> anyhow::Result<Self> {
-        let conn = TcpStream::connect(format!("{}:{}", server, port))
-            .await
-            .map_err(|e| anyhow!("Got error while connect to {}:{} {:?}", server, port, e))?;
-
-        //let bufreader = BufReader::new(conn);
-        //conn.set_nonblocking(true).unwrap();
-        let mut self_ = Self { conn };
+    async fn open_stream(
+        server: &str,
+        port: u16,
+        use_ssl: bool,
+        connect_timeout: Duration,
+        proxy: Option<&Proxy>,
+    ) -> anyhow::Result<Box<dyn QueryStream>> {
+        let host = strip_brackets(server);
+        let stream = tokio::time::timeout(connect_timeout, async {
+            match proxy {
+                Some(proxy) => proxy.connect(host, port).await,
+                None => connect_tcp(host, port).await,
+            }
+        })
+        .await
+        .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)))
+        // Keep io::Error in chain, so caller can classify it
+        .map_err(|e| {
+            anyhow::Error::new(e).context(format!("Got error while connect to {}:{}", server, port))
+        })?;
+        if !use_ssl {
+            return Ok(Box::new(stream));
+        }
 
-        let content = self_
-            .read_data()
+        let mut root_store = RootCertStore::empty();
+        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(
+            |anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    anchor.subject,
+                    anchor.spki,
+                    anchor.name_constraints,
+                )
+            },
+        ));
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(host)
+            .map_err(|e| anyhow!("Got invalid server name {:?}: {:?}", server, e))?;
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
             .await
-            .map_err(|e| anyhow!("Got error in connect while read content: {:?}", e))?;
+            .map_err(|e| anyhow::Error::new(e).context("Got error while TLS handshake"))?;
+        Ok(Box::new(stream))
+    }
 
-        if content.is_none() {
-            warn!("Read none data.");
+    /// Consume banner lines, so they will not be mixed into first command reply
+    async fn read_welcome(&mut self) -> anyhow::Result<()> {
+        let mut content = String::new();
+        while !content.contains("Welcome to the TeamSpeak 3") {
+            match self
+                .read_data()
+                .await
+                .map_err(|e| anyhow!("Got error in connect while read content: {:?}", e))?
+            {
+                Some(data) => content.push_str(&data),
+                None => {
+                    warn!("Read none data.");
+                    break;
+                }
+            }
         }
+        Ok(())
+    }
+
+    pub async fn connect(
+        server: &str,
+        port: u16,
+        use_ssl: bool,
+        connect_timeout: Duration,
+        proxy: Option<Proxy>,
+    ) -> anyhow::Result<Self> {
+        let conn =
+            Self::open_stream(server, port, use_ssl, connect_timeout, proxy.as_ref()).await?;
+        let mut self_ = Self::new(
+            conn,
+            (server.to_string(), port),
+            use_ssl,
+            connect_timeout,
+            proxy,
+        );
+        self_.read_welcome().await?;
+
+        Ok(self_)
+    }
 
+    /// Use established stream, e.g. scripted server in tests. `reconnect` is not supported.
+    pub async fn from_stream(stream: impl QueryStream + 'static) -> anyhow::Result<Self> {
+        let mut self_ = Self::new(
+            Box::new(stream),
+            (String::new(), 0),
+            false,
+            DEFAULT_CONNECT_TIMEOUT,
+            None,
+        );
+        self_.read_welcome().await?;
         Ok(self_)
     }
 
+    fn new(
+        conn: Box<dyn QueryStream>,
+        address: (String, u16),
+        use_ssl: bool,
+        connect_timeout: Duration,
+        proxy: Option<Proxy>,
+    ) -> Self {
+        Self {
+            conn,
+            fallback_encoding: None,
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            command_interval: Duration::ZERO,
+            last_command: None,
+            resync: false,
+            partial: Vec::new(),
+            pending_notifies: Vec::new(),
+            address,
+            use_ssl,
+            connect_timeout,
+            proxy,
+            credentials: None,
+            server_id: None,
+            registered_events: Vec::new(),
+        }
+    }
+
+    /// Re-establish connection, then login, select server and register events again.
+    pub async fn reconnect(&mut self) -> anyhow::Result<()> {
+        self.conn = Self::open_stream(
+            &self.address.0,
+            self.address.1,
+            self.use_ssl,
+            self.connect_timeout,
+            self.proxy.as_ref(),
+        )
+        .await?;
+        self.resync = false;
+        self.partial.clear();
+        self.pending_notifies.clear();
+        self.read_welcome().await?;
+        if let Some(credential) = self.credentials.clone() {
+            self.authenticate(&credential)
+                .await
+                .map_err(|e| anyhow!("Login failed. {:?}", e))?;
+        }
+        if let Some(server_id) = self.server_id {
+            self.select_server(server_id)
+                .await
+                .map_err(|e| anyhow!("Select server id failed: {:?}", e))?;
+        }
+        for (class, channel_id) in self.registered_events.clone() {
+            self.register_event(class, channel_id)
+                .await
+                .map_err(|e| anyhow!("Got error while register events: {:?}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Selected virtual server id
+    pub fn server_id(&self) -> Option<i64> {
+        self.server_id
+    }
+
     pub async fn login(&mut self, user: &str, password: &str) -> QueryResult<()> {
-        let payload = format!("login {} {}\n\r", user, password);
-        self.basic_operation(payload.as_str()).await
+        self.authenticate(&Credential::Login {
+            user: user.to_string(),
+            password: password.to_string(),
+        })
+        .await
+    }
+
+    /// `login` with user and password, or `auth` with API key
+    pub async fn authenticate(&mut self, credential: &Credential) -> QueryResult<()> {
+        let payload = match credential {
+            Credential::Login { user, password } => {
+                format!("login {} {}\n\r", escape(user), escape(password))
+            }
+            Credential::ApiKey(key) => format!("auth apikey={}\n\r", escape(key)),
+        };
+        self.basic_operation(payload.as_str()).await?;
+        self.credentials = Some(credential.clone());
+        Ok(())
     }
 
     pub async fn select_server(&mut self, server_id: i64) -> QueryResult<()> {
         let payload = format!("use {}\n\r", server_id);
-        self.basic_operation(payload.as_str()).await
+        self.basic_operation(payload.as_str()).await?;
+        self.server_id = Some(server_id);
+        Ok(())
     }
 
     pub async fn query_clients(&mut self) -> QueryResult<Vec<Client>> {
-        self.query_operation_non_error("clientlist\n\r").await
+        self.query_operation_non_error("clientlist -uid -times -groups -country -ip\n\r")
+            .await
+    }
+
+    pub async fn query_channels(&mut self) -> QueryResult<Vec<Channel>> {
+        self.query_operation_non_error("channellist\n\r").await
+    }
+
+    pub async fn client_info(&mut self, client_id: i64) -> QueryResult<ClientInfo> {
+        let payload = format!("clientinfo clid={}\n\r", client_id);
+        self.query_operation_non_error(payload.as_str())
+            .await?
+            .pop()
+            .ok_or_else(QueryError::static_empty_response)
+    }
+
+    pub async fn server_info(&mut self) -> QueryResult<ServerInfo> {
+        self.query_operation_non_error("serverinfo\n\r")
+            .await?
+            .pop()
+            .ok_or_else(QueryError::static_empty_response)
+    }
+
+    pub async fn who_am_i(&mut self) -> QueryResult<WhoAmI> {
+        self.query_operation_non_error("whoami\n\r")
+            .await?
+            .pop()
+            .ok_or_else(QueryError::static_empty_response)
+    }
+
+    pub async fn global_message(&mut self, message: &str) -> QueryResult<()> {
+        let payload = format!("gm msg={}\n\r", escape(message));
+        self.basic_operation(payload.as_str()).await
+    }
+
+    /// Send message to server chat of current virtual server
+    pub async fn send_text_message(&mut self, message: &str) -> QueryResult<()> {
+        let payload = format!(
+            "sendtextmessage targetmode=3 target={} msg={}\n\r",
+            self.server_id.unwrap_or(1),
+            escape(message)
+        );
+        self.basic_operation(payload.as_str()).await
+    }
+
+    /// Kick client from server
+    pub async fn kick_client(&mut self, client_id: i64) -> QueryResult<()> {
+        let payload = format!("clientkick reasonid=5 clid={}\n\r", client_id);
+        self.basic_operation(payload.as_str()).await
+    }
+
+    /// Ban client for `seconds`, zero means permanent
+    pub async fn ban_client(&mut self, client_id: i64, seconds: u64) -> QueryResult<()> {
+        let payload = format!("banclient clid={} time={}\n\r", client_id, seconds);
+        self.basic_operation(payload.as_str()).await
     }
 
     pub async fn logout(&mut self) -> anyhow::Result<()> {
         self.write_data("quit\n\r").await
     }
 
-    pub async fn register_events(&mut self) -> QueryResult<()> {
-        self.basic_operation("servernotifyregister event=server\n\r")
+    pub async fn register_event(
+        &mut self,
+        class: EventClass,
+        channel_id: Option<i64>,
+    ) -> QueryResult<()> {
+        let payload = match channel_id {
+            Some(channel_id) => format!(
+                "servernotifyregister event={} id={}\n\r",
+                class.as_str(),
+                channel_id
+            ),
+            None => format!("servernotifyregister event={}\n\r", class.as_str()),
+        };
+        self.basic_operation(payload.as_str()).await?;
+        if !self.registered_events.contains(&(class, channel_id)) {
+            self.registered_events.push((class, channel_id));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        connect_tcp, escape, flood_wait, strip_brackets, unescape, Credential, SocketConn,
+    };
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_discard_oversized_data() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"TS3\n\rWelcome to the TeamSpeak 3 ServerQuery interface\n\r")
+                .await
+                .unwrap();
+            ready_receiver.await.unwrap();
+            let mut payload = vec![b'a'; 4000];
+            payload.extend_from_slice(b"\n\rnotifyclientleftview clid=1\n\r");
+            stream.write_all(&payload).await.unwrap();
+            // Keep connection open until client finished
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        });
+
+        let mut conn = SocketConn::connect("127.0.0.1", port, false, Duration::from_secs(5), None)
+            .await
+            .unwrap();
+        conn.set_max_buffer_size(1024);
+        ready_sender.send(()).unwrap();
+
+        let mut received = String::new();
+        while !received.contains("notifyclientleftview") {
+            let data = conn.read_data().await.unwrap().unwrap_or_default();
+            assert!(!data.contains("a".repeat(1025).as_str()));
+            received.push_str(&data);
+        }
+        assert!(received.ends_with("notifyclientleftview clid=1\n\r"));
+    }
+
+    #[tokio::test]
+    async fn test_notify_before_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"TS3\n\rWelcome to the TeamSpeak 3 ServerQuery interface\n\r")
+                .await
+                .unwrap();
+            let mut buffer = [0u8; 64];
+            stream.read(&mut buffer).await.unwrap();
+            stream
+                .write_all(b"notifyclientleftview cfid=1 ctid=0 reasonid=8 clid=2\n\r")
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            stream
+                .write_all(b"client_id=1 virtualserver_id=1\n\rerror id=0 msg=ok\n\r")
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        });
+
+        let mut conn = SocketConn::connect("127.0.0.1", port, false, Duration::from_secs(5), None)
+            .await
+            .unwrap();
+        assert_eq!(conn.who_am_i().await.unwrap().client_id(), 1);
+        let data = conn.read_data().await.unwrap().unwrap();
+        assert!(data.starts_with("notifyclientleftview"));
+    }
+
+    #[tokio::test]
+    async fn test_notify_after_reply() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            server
+                .write_all(b"TS3\n\rWelcome to the TeamSpeak 3 ServerQuery interface\n\r")
+                .await
+                .unwrap();
+            let mut buffer = [0u8; 64];
+            server.read(&mut buffer).await.unwrap();
+            // Reply and notify arrive in one chunk
+            server
+                .write_all(b"client_id=1 virtualserver_id=1\n\rerror id=0 msg=ok\n\rnotifyclientleftview cfid=1 ctid=0 reasonid=8 clid=2\n\r")
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        });
+
+        let mut conn = SocketConn::from_stream(client).await.unwrap();
+        assert_eq!(conn.who_am_i().await.unwrap().client_id(), 1);
+        let data = conn.read_data().await.unwrap().unwrap();
+        assert_eq!(
+            data,
+            "notifyclientleftview cfid=1 ctid=0 reasonid=8 clid=2\n\r"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_apikey() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"TS3\n\rWelcome to the TeamSpeak 3 ServerQuery interface\n\r")
+                .await
+                .unwrap();
+            let mut buffer = [0u8; 64];
+            let size = stream.read(&mut buffer).await.unwrap();
+            sender
+                .send(String::from_utf8_lossy(&buffer[..size]).to_string())
+                .unwrap();
+            stream.write_all(b"error id=0 msg=ok\n\r").await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        });
+
+        let mut conn = SocketConn::connect("127.0.0.1", port, false, Duration::from_secs(5), None)
+            .await
+            .unwrap();
+        conn.authenticate(&Credential::ApiKey("key with+space".to_string()))
             .await
+            .unwrap();
+        assert_eq!(receiver.await.unwrap(), "auth apikey=key\\swith+space\n\r");
+    }
+
+    #[tokio::test]
+    async fn test_small_read_buffer() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        server
+            .write_all(b"TS3\n\rWelcome to the TeamSpeak 3 ServerQuery interface\n\r")
+            .await
+            .unwrap();
+        let mut conn = SocketConn::from_stream(client).await.unwrap();
+        conn.set_read_buffer_size(7);
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 64];
+            server.read(&mut buffer).await.unwrap();
+            server
+                .write_all(b"cid=1 pid=0 channel_name=Lobby|cid=2 pid=0 channel_name=AFK\n\rerror id=0 msg=ok\n\r")
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        });
+        let channels = conn.query_channels().await.unwrap();
+        assert_eq!(channels.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_connect_tcp() {
+        assert_eq!(strip_brackets("[::1]"), "::1");
+        assert_eq!(strip_brackets("::1"), "::1");
+        assert_eq!(strip_brackets("example.com"), "example.com");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // localhost may resolve to ::1 first, which is refused here
+        assert!(connect_tcp("localhost", port).await.is_ok());
+        assert!(connect_tcp("127.0.0.1", port).await.is_ok());
+    }
+
+    #[test]
+    fn test_flood_wait() {
+        assert_eq!(flood_wait("error id=0 msg=ok\n\r"), None);
+        assert_eq!(
+            flood_wait(
+                "error id=524 msg=client\\sis\\sflooding extra_msg=please\\swait\\s3\\sseconds\n\r"
+            ),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            flood_wait("error id=524 msg=client\\sis\\sflooding\n\r"),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        let raw = "pass word\\with/pipe|\n";
+        let escaped = escape(raw);
+        assert_eq!(escaped, "pass\\sword\\\\with\\/pipe\\p\\n");
+        assert_eq!(unescape(&escaped), raw);
+    }
+
+    #[tokio::test]
+    async fn test_split_line() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"TS3\n\r").await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            stream
+                .write_all(b"Welcome to the TeamSpeak 3 ServerQuery interface\n\r")
+                .await
+                .unwrap();
+            stream
+                .write_all(b"notifyclientleftview cfid=1 ctid=0 rea")
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            stream
+                .write_all(
+                    b"sonid=8 clid=2\n\rnotifyclientleftview cfid=1 ctid=0 reasonid=8 clid=3",
+                )
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            stream.write_all(b"\n\r").await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        });
+
+        let mut conn = SocketConn::connect("127.0.0.1", port, false, Duration::from_secs(5), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            conn.read_data().await.unwrap().unwrap(),
+            "notifyclientleftview cfid=1 ctid=0 reasonid=8 clid=2\n\r"
+        );
+        assert_eq!(
+            conn.read_data().await.unwrap().unwrap(),
+            "notifyclientleftview cfid=1 ctid=0 reasonid=8 clid=3\n\r"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_split_terminator() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"TS3\n\rWelcome to the TeamSpeak 3 ServerQuery interface\n\r")
+                .await
+                .unwrap();
+            // Terminator and multi-byte character are both split between two segments
+            stream
+                .write_all(b"notifyclientleftview clid=2 reasonmsg=\xe4\xbd")
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            stream.write_all(b"\xa0\n").await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            stream.write_all(b"\r").await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        });
+
+        let mut conn = SocketConn::connect("127.0.0.1", port, false, Duration::from_secs(5), None)
+            .await
+            .unwrap();
+        let mut received = String::new();
+        while received.is_empty() {
+            received = conn.read_data().await.unwrap().unwrap_or_default();
+        }
+        assert_eq!(received, "notifyclientleftview clid=2 reasonmsg=你\n\r");
     }
 }
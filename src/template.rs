@@ -0,0 +1,365 @@
+use anyhow::anyhow;
+use log::{error, info};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Placeholder {
+    Time,
+    Nickname,
+    Uid,
+    Clid,
+    Country,
+    Flag,
+    Reason,
+    /// Left, kicked, banned or timed out
+    Departure,
+    ChannelName,
+    ChannelPath,
+    Platform,
+    Version,
+    Duration,
+    /// Per-server `label` from configure file, empty if not set
+    ServerLabel,
+    /// Recognized badges and flags, empty unless `show_badges` is enabled
+    Badges,
+}
+
+impl FromStr for Placeholder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "time" => Self::Time,
+            "nickname" => Self::Nickname,
+            "uid" => Self::Uid,
+            "clid" => Self::Clid,
+            "country" => Self::Country,
+            "flag" => Self::Flag,
+            "reason" => Self::Reason,
+            "departure" => Self::Departure,
+            "channel_name" => Self::ChannelName,
+            "channel_path" => Self::ChannelPath,
+            "platform" => Self::Platform,
+            "version" => Self::Version,
+            "duration" => Self::Duration,
+            "server_label" => Self::ServerLabel,
+            "badges" => Self::Badges,
+            _ => return Err(anyhow!("Unknown placeholder: {{{}}}", s)),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// Compiled message template, use `{{` and `}}` to output literal braces.
+#[derive(Clone, Debug)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn compile(s: &str) -> anyhow::Result<Self> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(anyhow!("Unclosed placeholder: {{{}", name)),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Placeholder(name.trim().parse()?));
+                }
+                '}' => return Err(anyhow!("Unmatched '}}' in template")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn render(&self, value: &dyn Fn(Placeholder) -> String) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(s) => s.clone(),
+                Segment::Placeholder(placeholder) => value(*placeholder),
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Contains,
+}
+
+/// Clauses joined by `&&`, each clause looks like `country == DE`, `uid != abc` or `nickname ~= bot`.
+#[derive(Clone, Debug)]
+pub struct Condition {
+    clauses: Vec<(Placeholder, Operator, String)>,
+}
+
+impl Condition {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut clauses = Vec::new();
+        for clause in s.split("&&") {
+            let (operator, (key, value)) = [
+                ("==", Operator::Equal),
+                ("!=", Operator::NotEqual),
+                ("~=", Operator::Contains),
+            ]
+            .iter()
+            .find_map(|(token, operator)| clause.split_once(token).map(|x| (*operator, x)))
+            .ok_or_else(|| anyhow!("Unknown operator in condition: {:?}", clause))?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            clauses.push((key.trim().parse()?, operator, value.to_string()));
+        }
+        Ok(Self { clauses })
+    }
+
+    pub fn matches(&self, value: &dyn Fn(Placeholder) -> String) -> bool {
+        self.clauses.iter().all(|(placeholder, operator, expect)| {
+            let actual = value(*placeholder);
+            match operator {
+                Operator::Equal => actual.eq(expect),
+                Operator::NotEqual => actual.ne(expect),
+                Operator::Contains => actual.contains(expect.as_str()),
+            }
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct RawRule {
+    condition: Option<String>,
+    format: String,
+}
+
+/// Template which is used only when condition matches, no condition matches everything.
+#[derive(Clone, Debug)]
+struct Rule {
+    condition: Option<Condition>,
+    template: Template,
+}
+
+impl TryFrom<&RawRule> for Rule {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: &RawRule) -> Result<Self, Self::Error> {
+        Ok(Self {
+            condition: raw.condition.as_deref().map(Condition::parse).transpose()?,
+            template: Template::compile(&raw.format)?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct RawTemplates {
+    enter: Option<String>,
+    left: Option<String>,
+    #[serde(default)]
+    enter_rules: Vec<RawRule>,
+    #[serde(default)]
+    left_rules: Vec<RawRule>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TemplateKind {
+    Enter,
+    Left,
+}
+
+/// Templates for each event, empty means use built-in format.
+///
+/// Rules are evaluated in order and first match wins, plain `enter`/`left` template is the fallback.
+#[derive(Clone, Debug, Default)]
+pub struct Templates {
+    enter: Vec<Rule>,
+    left: Vec<Rule>,
+}
+
+fn compile_rules(rules: &[RawRule], default: &Option<String>) -> anyhow::Result<Vec<Rule>> {
+    let mut ret = rules
+        .iter()
+        .map(Rule::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(default) = default {
+        ret.push(Rule {
+            condition: None,
+            template: Template::compile(default)?,
+        });
+    }
+    Ok(ret)
+}
+
+impl Templates {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Got error while read template file: {:?}", e))?;
+        let raw: RawTemplates = toml::from_str(&content)
+            .map_err(|e| anyhow!("Got error while parse template file: {:?}", e))?;
+        Ok(Self {
+            enter: compile_rules(&raw.enter_rules, &raw.enter)?,
+            left: compile_rules(&raw.left_rules, &raw.left)?,
+        })
+    }
+
+    /// Build from templates written in config file, `None` means built-in format
+    pub fn inline(enter: Option<&str>, left: Option<&str>) -> anyhow::Result<Self> {
+        let compile = |s: Option<&str>| -> anyhow::Result<Vec<Rule>> {
+            Ok(match s {
+                Some(s) => vec![Rule {
+                    condition: None,
+                    template: Template::compile(s)?,
+                }],
+                None => vec![],
+            })
+        };
+        Ok(Self {
+            enter: compile(enter)?,
+            left: compile(left)?,
+        })
+    }
+
+    /// Find first template whose condition matches
+    pub fn select(
+        &self,
+        kind: TemplateKind,
+        value: &dyn Fn(Placeholder) -> String,
+    ) -> Option<&Template> {
+        match kind {
+            TemplateKind::Enter => &self.enter,
+            TemplateKind::Left => &self.left,
+        }
+        .iter()
+        .find(|rule| match &rule.condition {
+            Some(condition) => condition.matches(value),
+            None => true,
+        })
+        .map(|rule| &rule.template)
+    }
+}
+
+pub type SharedTemplates = Arc<RwLock<Templates>>;
+
+/// Watch template file and reload it on change, invalid file keeps previous templates.
+///
+/// Parent directory is watched, so editors which replace file by renaming still work.
+/// Returned watcher should be kept alive as long as reload is wanted.
+pub fn watch_templates(
+    path: PathBuf,
+    templates: SharedTemplates,
+) -> anyhow::Result<RecommendedWatcher> {
+    let directory = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+        .to_path_buf();
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                error!("Got error while watch template file: {:?}", e);
+                return;
+            }
+        };
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            || !event
+                .paths
+                .iter()
+                .any(|changed| changed.file_name() == file_name.as_deref())
+        {
+            return;
+        }
+        match Templates::load(&path) {
+            Ok(new) => {
+                *templates.write().unwrap() = new;
+                info!("Templates reloaded from {}", path.display());
+            }
+            Err(e) => error!("Keep previous templates, reload failed: {:?}", e),
+        }
+    })?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Condition, Placeholder, Template};
+
+    #[test]
+    fn test_template() {
+        let template = Template::compile("[{time}] <b>{nickname}</b> {{{clid}}}").unwrap();
+        assert_eq!(
+            template.render(&|placeholder| match placeholder {
+                Placeholder::Time => "now".to_string(),
+                Placeholder::Nickname => "Alice".to_string(),
+                Placeholder::Clid => "5".to_string(),
+                _ => String::new(),
+            }),
+            "[now] <b>Alice</b> {5}"
+        );
+        let template = Template::compile("{server_label} {nickname}").unwrap();
+        assert_eq!(
+            template.render(&|placeholder| match placeholder {
+                Placeholder::ServerLabel => "[Main]".to_string(),
+                Placeholder::Nickname => "Alice".to_string(),
+                _ => String::new(),
+            }),
+            "[Main] Alice"
+        );
+        assert!(Template::compile("{unknown}").is_err());
+        assert!(Template::compile("{time").is_err());
+        assert!(Template::compile("time}").is_err());
+    }
+
+    #[test]
+    fn test_condition() {
+        let value = |placeholder| match placeholder {
+            Placeholder::Country => "DE".to_string(),
+            Placeholder::Nickname => "MusicBot 2".to_string(),
+            _ => String::new(),
+        };
+        assert!(Condition::parse("country == DE").unwrap().matches(&value));
+        assert!(Condition::parse("country == \"DE\" && nickname ~= Bot")
+            .unwrap()
+            .matches(&value));
+        assert!(!Condition::parse("country != DE").unwrap().matches(&value));
+        assert!(Condition::parse("country > DE").is_err());
+        assert!(Condition::parse("unknown == DE").is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::anyhow;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, Local, Utc};
+
+const DEFAULT_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Timezone of displayed time, parsed from `Local`, `UTC`, `+08:00` or IANA name like `Asia/Tokyo`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DisplayTimezone {
+    Local,
+    Utc,
+    Fixed(FixedOffset),
+    Named(chrono_tz::Tz),
+}
+
+impl DisplayTimezone {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("local") {
+            return Ok(Self::Local);
+        }
+        if s.eq_ignore_ascii_case("utc") {
+            return Ok(Self::Utc);
+        }
+        if let Some(sign) = s.chars().next().filter(|c| *c == '+' || *c == '-') {
+            let (hours, minutes) = s[1..].split_once(':').unwrap_or((&s[1..], "0"));
+            let seconds = match (hours.parse::<i32>(), minutes.parse::<i32>()) {
+                (Ok(hours), Ok(minutes)) if minutes < 60 => hours * 3600 + minutes * 60,
+                _ => return Err(anyhow!("Invalid timezone offset: {}", s)),
+            };
+            let seconds = if sign == '-' { -seconds } else { seconds };
+            return FixedOffset::east_opt(seconds)
+                .map(Self::Fixed)
+                .ok_or_else(|| anyhow!("Timezone offset out of range: {}", s));
+        }
+        s.parse::<chrono_tz::Tz>()
+            .map(Self::Named)
+            .map_err(|e| anyhow!("Unknown timezone {:?}: {}", s, e))
+    }
+}
+
+/// How event time is displayed in messages
+#[derive(Clone, Debug)]
+pub struct TimeDisplay {
+    format: String,
+    timezone: DisplayTimezone,
+}
+
+impl Default for TimeDisplay {
+    fn default() -> Self {
+        Self {
+            format: DEFAULT_FORMAT.to_string(),
+            timezone: DisplayTimezone::Local,
+        }
+    }
+}
+
+impl TimeDisplay {
+    pub fn new(format: Option<&str>, timezone: Option<&str>) -> anyhow::Result<Self> {
+        let format = format.unwrap_or(DEFAULT_FORMAT);
+        // Bad specifier only fails when formatting, check it early
+        if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+            return Err(anyhow!("Invalid time format: {:?}", format));
+        }
+        Ok(Self {
+            format: format.to_string(),
+            timezone: timezone
+                .map(DisplayTimezone::parse)
+                .transpose()?
+                .unwrap_or(DisplayTimezone::Local),
+        })
+    }
+
+    pub fn display(&self, time: DateTime<Utc>) -> String {
+        let format = self.format.as_str();
+        match &self.timezone {
+            DisplayTimezone::Local => time.with_timezone(&Local).format(format).to_string(),
+            DisplayTimezone::Utc => time.format(format).to_string(),
+            DisplayTimezone::Fixed(offset) => time.with_timezone(offset).format(format).to_string(),
+            DisplayTimezone::Named(tz) => time.with_timezone(tz).format(format).to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DisplayTimezone, TimeDisplay};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_time_display() {
+        let time = Utc.with_ymd_and_hms(2022, 7, 1, 12, 30, 0).unwrap();
+        let display = TimeDisplay::new(Some("%Y-%m-%dT%H:%M:%S%:z"), Some("UTC")).unwrap();
+        assert_eq!(display.display(time), "2022-07-01T12:30:00+00:00");
+        let display = TimeDisplay::new(Some("%H:%M"), Some("+08:00")).unwrap();
+        assert_eq!(display.display(time), "20:30");
+        let display = TimeDisplay::new(Some("%H:%M"), Some("-05:30")).unwrap();
+        assert_eq!(display.display(time), "07:00");
+        let display = TimeDisplay::new(Some("%H:%M %Z"), Some("Asia/Tokyo")).unwrap();
+        assert_eq!(display.display(time), "21:30 JST");
+        assert!(TimeDisplay::new(Some("%Q"), None).is_err());
+        assert!(DisplayTimezone::parse("Mars/Olympus").is_err());
+        assert!(DisplayTimezone::parse("+25:00").is_err());
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::anyhow;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Database ids of every client ever seen, persisted as one id per line.
+pub struct KnownVisitors {
+    path: PathBuf,
+    ids: HashSet<i64>,
+}
+
+impl KnownVisitors {
+    /// Empty set if file does not exist
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let ids = if path.exists() {
+            std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Got error while read known visitors: {:?}", e))?
+                .lines()
+                .filter_map(|line| line.trim().parse().ok())
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            ids,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Return true if database id was never seen before, new id is appended to file at once.
+    pub fn record(&mut self, database_id: i64) -> anyhow::Result<bool> {
+        if !self.ids.insert(database_id) {
+            return Ok(false);
+        }
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", database_id))
+            .map_err(|e| anyhow!("Got error while write known visitors: {:?}", e))?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KnownVisitors;
+
+    #[test]
+    fn test_record() {
+        let path = std::env::temp_dir().join(format!("visitors-{}.txt", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let mut visitors = KnownVisitors::load(&path).unwrap();
+        assert!(visitors.is_empty());
+        assert!(visitors.record(5).unwrap());
+        assert!(!visitors.record(5).unwrap());
+        assert!(visitors.record(6).unwrap());
+        let mut visitors = KnownVisitors::load(&path).unwrap();
+        assert!(!visitors.record(5).unwrap());
+        assert!(!visitors.record(6).unwrap());
+        assert!(visitors.record(7).unwrap());
+        std::fs::remove_file(&path).ok();
+    }
+}
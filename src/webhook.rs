@@ -0,0 +1,168 @@
+use crate::format::MessageFormat;
+use crate::retry::Backoff;
+use crate::TelegramData;
+use log::{debug, error, info, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Event as JSON object, fields not applicable to event type are `null`.
+fn payload(data: &TelegramData) -> Option<Value> {
+    let mut client_id = None;
+    let mut unique_identifier = None;
+    let mut nickname = None;
+    let mut country = None;
+    let mut reason = None;
+    let mut channel = None;
+    let event = match data {
+        TelegramData::Enter(_, _, view, name, _, _, label) => {
+            client_id = Some(view.client_id());
+            unique_identifier = Some(view.client_unique_identifier().to_string());
+            nickname = Some(name.clone());
+            country = Some(view.client_country().to_string());
+            channel = Some(label.name().to_string());
+            "enter"
+        }
+        TelegramData::Left(_, _, view, name, _) => {
+            client_id = Some(view.client_id());
+            nickname = Some(name.clone());
+            reason = Some(view.reason().to_string());
+            "left"
+        }
+        TelegramData::Moved(_, _, view, name, label) => {
+            client_id = Some(view.client_id());
+            nickname = Some(name.clone());
+            channel = Some(label.name().to_string());
+            "moved"
+        }
+        TelegramData::Renamed(_, _, id, _, new) => {
+            client_id = Some(*id);
+            nickname = Some(new.clone());
+            "renamed"
+        }
+        TelegramData::Text(_, _, view, name) => {
+            client_id = Some(view.invoker_id());
+            unique_identifier = Some(view.invoker_uid().to_string());
+            nickname = Some(name.clone());
+            "text"
+        }
+        TelegramData::SessionAlert(_, _, uid, name, _) => {
+            unique_identifier = Some(uid.clone());
+            nickname = Some(name.clone());
+            "multisession"
+        }
+        TelegramData::PacketLoss(_, _, id, name, _) => {
+            client_id = Some(*id);
+            nickname = Some(name.clone());
+            "packetloss"
+        }
+        TelegramData::Idle(_, _, id, name, _) => {
+            client_id = Some(*id);
+            nickname = Some(name.clone());
+            "idle"
+        }
+        TelegramData::FirstSeen(_, _, id, name) => {
+            client_id = Some(*id);
+            nickname = Some(name.clone());
+            "first_seen"
+        }
+        TelegramData::Digest(..) => "digest",
+        TelegramData::Status(..) => "status",
+        TelegramData::ServerStatus(..) => "server_status",
+        TelegramData::Startup(..) => "startup",
+        TelegramData::Shutdown(..) => "shutdown",
+        TelegramData::SessionTerminated(_, _, view) => {
+            reason = Some(view.reason().to_string());
+            "session_terminated"
+        }
+        TelegramData::Alert(..) => "alert",
+        TelegramData::Terminate => return None,
+    };
+    Some(json!({
+        "type": event,
+        "sequence": data.sequence(),
+        "timestamp": data.time().map(|time| time.to_rfc3339()),
+        "clid": client_id,
+        "uid": unique_identifier,
+        "nickname": nickname,
+        "country": country,
+        "reason": reason,
+        "channel": channel,
+        "message": data.format(MessageFormat::None),
+    }))
+}
+
+fn header_map(headers: &HashMap<String, String>) -> anyhow::Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        map.insert(
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| anyhow::anyhow!("Got error while parse header {:?}: {:?}", name, e))?,
+            HeaderValue::from_str(value)
+                .map_err(|e| anyhow::anyhow!("Got error while parse header {:?}: {:?}", name, e))?,
+        );
+    }
+    Ok(map)
+}
+
+pub async fn webhook_thread(
+    url: String,
+    headers: HashMap<String, String>,
+    timeout: Duration,
+    max_retries: u32,
+    is_leader: Arc<AtomicBool>,
+    dry_run: bool,
+    mut receiver: mpsc::Receiver<TelegramData>,
+) -> anyhow::Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .default_headers(header_map(&headers)?)
+        .build()?;
+    while let Some(cmd) = receiver.recv().await {
+        if let TelegramData::Terminate = cmd {
+            break;
+        }
+        if !is_leader.load(Ordering::Relaxed) {
+            debug!("Standby instance, drop event #{:?}", cmd.sequence());
+            continue;
+        }
+        let body = match payload(&cmd) {
+            Some(body) => body,
+            None => continue,
+        };
+        if dry_run {
+            info!("[dry run] Send webhook: {}", body);
+            continue;
+        }
+        // Only server side errors are retried, 4xx means payload or url is wrong
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+        for attempt in 0..=max_retries {
+            match client.post(&url).json(&body).send().await {
+                Ok(response) if response.status().is_server_error() && attempt < max_retries => {
+                    warn!(
+                        "Webhook got {}, retry {}/{}",
+                        response.status(),
+                        attempt + 1,
+                        max_retries
+                    );
+                    tokio::time::sleep(backoff.next_delay()).await;
+                }
+                Ok(response) if !response.status().is_success() => {
+                    warn!("Webhook got unexpected status: {}", response.status());
+                    break;
+                }
+                Ok(_) => break,
+                Err(e) => {
+                    error!("Got error while send webhook: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+    debug!("Webhook daemon exiting...");
+    Ok(())
+}